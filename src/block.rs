@@ -1,42 +1,254 @@
-use crate::transaction::Transaction;
+use crate::{
+    merkle::{merkle_proof, merkle_root, MerkleProof},
+    pow::meets_difficulty,
+    state::StateError,
+    transaction::{Transaction, TransactionError},
+};
+use derive_more::Display;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::cmp::PartialEq;
 use std::fmt;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
+
+/// Reason a block was rejected by `Block::validate`.
+#[derive(Clone, Display, PartialEq, Debug)]
+pub enum BlockError {
+    #[display(fmt = "chain starts at {} instead of genesis {}", found, expected)]
+    GenesisMismatch { expected: String, found: String },
+    #[display(fmt = "prev hash {} does not match tip {}", found, expected)]
+    PrevHashMismatch { expected: String, found: String },
+    #[display(fmt = "unsupported block version {}", _0)]
+    UnsupportedVersion(u32),
+    #[display(fmt = "stored hash {} does not match computed {}", stored, computed)]
+    HashMismatch { stored: String, computed: String },
+    #[display(fmt = "merkle root {} does not match transactions {}", stored, computed)]
+    MerkleRootMismatch { stored: String, computed: String },
+    #[display(fmt = "block difficulty {} does not match expected {}", found, expected)]
+    DifficultyMismatch { expected: u32, found: u32 },
+    #[display(fmt = "hash {} does not meet difficulty {}", hash, difficulty)]
+    InsufficientWork { hash: String, difficulty: u32 },
+    #[display(fmt = "transaction {} is invalid: {}", index, reason)]
+    InvalidTransaction {
+        index: usize,
+        reason: TransactionError,
+    },
+    #[display(fmt = "transaction {} is rejected: {}", index, reason)]
+    StateViolation { index: usize, reason: StateError },
+    #[display(fmt = "block takes {} bytes but at most {} are allowed", size, limit)]
+    Oversized { size: usize, limit: usize },
+    #[display(fmt = "block does not start with a coinbase transaction")]
+    MissingCoinbase,
+    #[display(fmt = "transaction {} is a coinbase but not the first", _0)]
+    MisplacedCoinbase(usize),
+    #[display(fmt = "timestamp {} is not after the median {} of recent blocks", timestamp, median)]
+    TimestampTooEarly { timestamp: u64, median: u64 },
+    #[display(fmt = "timestamp {} is ahead of the local clock, at most {} is allowed", timestamp, limit)]
+    TimestampInFuture { timestamp: u64, limit: u64 },
+}
+
+impl std::error::Error for BlockError {}
+
+/// Header encoding understood by this node. Bumped whenever the layout
+/// hashed by `BlockHeader::encode` changes.
+pub const BLOCK_VERSION: u32 = 1;
+
+/// The part of a block that is hashed and mined. Transactions are committed
+/// to through `merkle_root` only.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct BlockHeader {
+    pub version: u32,
+    pub prev_hash: String,
+    /// Root of the merkle tree over the transaction ids
+    pub merkle_root: String,
+    /// Milliseconds since the unix epoch
+    pub timestamp: u64,
+    /// Leading zero bits the hash was mined to.
+    pub difficulty: u32,
+    pub nonce: u64,
+}
+
+impl BlockHeader {
+    /// Canonical bytes hashed into the block hash: the version, then each
+    /// field in declaration order. Integers are little-endian and strings
+    /// carry a little-endian `u32` length prefix, so the encoding does not
+    /// depend on serde or the platform.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4 + 2 * (4 + 64) + 8 + 4 + 8);
+        bytes.extend_from_slice(&self.version.to_le_bytes());
+        encode_str(&mut bytes, &self.prev_hash);
+        encode_str(&mut bytes, &self.merkle_root);
+        bytes.extend_from_slice(&self.timestamp.to_le_bytes());
+        bytes.extend_from_slice(&self.difficulty.to_le_bytes());
+        bytes.extend_from_slice(&self.nonce.to_le_bytes());
+        bytes
+    }
+
+    pub fn hash(&self) -> String {
+        let hashed = Sha256::new().chain_update(self.encode()).finalize();
+        format!("{:x}", hashed)
+    }
+
+    pub fn time(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_millis(self.timestamp)
+    }
+
+    /// Checks that this node understands the header and that its hash meets
+    /// the difficulty it claims.
+    pub fn verify_work(&self) -> Result<(), BlockError> {
+        if self.version != BLOCK_VERSION {
+            return Err(BlockError::UnsupportedVersion(self.version));
+        }
+
+        let hash = self.hash();
+        if !meets_difficulty(&hash, self.difficulty) {
+            return Err(BlockError::InsufficientWork {
+                hash,
+                difficulty: self.difficulty,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) fn encode_str(bytes: &mut Vec<u8>, value: &str) {
+    encode_bytes(bytes, value.as_bytes());
+}
+
+pub(crate) fn encode_bytes(bytes: &mut Vec<u8>, value: &[u8]) {
+    bytes.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(value);
+}
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct Block {
     pub hash: String,
-    pub prev_hash: String,
+    pub header: BlockHeader,
     pub transactions: Vec<Transaction>,
-    pub time: SystemTime,
-    pub nonce: u64,
 }
 
 impl Block {
-    pub fn new(prev: String, txs: Vec<Transaction>, nonce: u64, ms: SystemTime) -> Self {
+    pub fn new(
+        prev: String,
+        txs: Vec<Transaction>,
+        difficulty: u32,
+        nonce: u64,
+        ms: SystemTime,
+    ) -> Self {
+        let timestamp = ms
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
         Block {
             hash: String::new(),
-            prev_hash: prev,
+            header: BlockHeader {
+                version: BLOCK_VERSION,
+                prev_hash: prev,
+                merkle_root: merkle_root(&tx_ids(&txs)),
+                timestamp,
+                difficulty,
+                nonce,
+            },
             transactions: txs,
-            nonce: nonce,
-            time: ms,
         }
     }
 
-    pub fn generate_hash(&mut self) -> String {
-        let block_string = serde_json::to_string(&self);
+    /// Hashes the block header. Transactions only count through the merkle
+    /// root, which `verify_transactions` checks separately.
+    pub fn calculate_hash(&self) -> String {
+        self.header.hash()
+    }
 
-        let hashed = Sha256::new().chain_update(block_string.unwrap()).finalize();
+    /// Bytes taken up by the transactions, which `max_block_size` limits.
+    pub fn size(&self) -> usize {
+        self.transactions.iter().map(Transaction::size).sum()
+    }
 
-        self.hash = format!("{:x}", hashed);
+    pub fn calculate_merkle_root(&self) -> String {
+        merkle_root(&tx_ids(&self.transactions))
+    }
+
+    /// Proof that the transaction with `tx_id` is in this block, checkable
+    /// against `merkle_root` alone.
+    pub fn merkle_proof(&self, tx_id: &str) -> Option<MerkleProof> {
+        let ids = tx_ids(&self.transactions);
+        let index = ids.iter().position(|id| id == tx_id)?;
+        merkle_proof(&ids, index)
+    }
+
+    pub fn generate_hash(&mut self) -> String {
+        self.hash = self.calculate_hash();
         self.hash.clone()
     }
 
-    pub fn is_valid(&self, prev_block: &Block) -> bool {
-        self.prev_hash == prev_block.hash
+    pub fn validate(&self, prev_block: &Block, difficulty: u32) -> Result<(), BlockError> {
+        if self.header.prev_hash != prev_block.hash {
+            return Err(BlockError::PrevHashMismatch {
+                expected: prev_block.hash.clone(),
+                found: self.header.prev_hash.clone(),
+            });
+        }
+
+        if self.header.difficulty != difficulty {
+            return Err(BlockError::DifficultyMismatch {
+                expected: difficulty,
+                found: self.header.difficulty,
+            });
+        }
+
+        self.verify_hash()?;
+        self.verify_transactions()
+    }
+
+    /// Checks that the transactions match the merkle root, that the block
+    /// opens with its only coinbase and that every other transaction is
+    /// signed by its sender.
+    pub fn verify_transactions(&self) -> Result<(), BlockError> {
+        let computed = self.calculate_merkle_root();
+        if computed != self.header.merkle_root {
+            return Err(BlockError::MerkleRootMismatch {
+                stored: self.header.merkle_root.clone(),
+                computed,
+            });
+        }
+
+        match self.transactions.first() {
+            Some(coinbase) if coinbase.is_coinbase() => {}
+            _ => return Err(BlockError::MissingCoinbase),
+        }
+
+        for (index, tx) in self.transactions.iter().enumerate().skip(1) {
+            if tx.is_coinbase() {
+                return Err(BlockError::MisplacedCoinbase(index));
+            }
+            tx.verify()
+                .map_err(|reason| BlockError::InvalidTransaction { index, reason })?;
+        }
+
+        Ok(())
     }
+
+    /// Checks the stored hash against the block contents and the difficulty
+    /// the block claims. Needs no parent, so it also screens orphans.
+    pub fn verify_hash(&self) -> Result<(), BlockError> {
+        if self.header.version != BLOCK_VERSION {
+            return Err(BlockError::UnsupportedVersion(self.header.version));
+        }
+
+        let computed = self.calculate_hash();
+        if computed != self.hash {
+            return Err(BlockError::HashMismatch {
+                stored: self.hash.clone(),
+                computed,
+            });
+        }
+
+        self.header.verify_work()
+    }
+}
+
+fn tx_ids(txs: &[Transaction]) -> Vec<String> {
+    txs.iter().map(Transaction::id).collect()
 }
 
 impl fmt::Display for Block {
@@ -48,13 +260,19 @@ impl fmt::Display for Block {
         let mut result_string = String::new();
         result_string.push_str(&("=".repeat(30) + "\r\n"));
         result_string.push_str(&("Hash: ".to_owned() + &self.hash + "\r\n"));
-        result_string.push_str(&("Prev Hash: ".to_owned() + &self.prev_hash + "\r\n"));
+        result_string
+            .push_str(&("Version: ".to_owned() + &self.header.version.to_string() + "\r\n"));
+        result_string.push_str(&("Prev Hash: ".to_owned() + &self.header.prev_hash + "\r\n"));
+        result_string
+            .push_str(&("Merkle Root: ".to_owned() + &self.header.merkle_root + "\r\n"));
         result_string
             .push_str(&("Tx len: ".to_owned() + &self.transactions.len().to_string() + "\r\n"));
         result_string
-            .push_str(&("Nonce: ".to_owned() + &self.nonce.to_string() + "\r\n"));
+            .push_str(&("Difficulty: ".to_owned() + &self.header.difficulty.to_string() + "\r\n"));
+        result_string
+            .push_str(&("Nonce: ".to_owned() + &self.header.nonce.to_string() + "\r\n"));
         result_string.push_str(
-            &("Time: ".to_owned() + &self.time.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64().to_string() + "\r\n"),
+            &("Time: ".to_owned() + &(self.header.timestamp as f64 / 1000.0).to_string() + "\r\n"),
         );
         result_string.push_str(&("=".repeat(30) + "\r\r\n\n"));
 
@@ -64,98 +282,229 @@ impl fmt::Display for Block {
 
 #[cfg(test)]
 pub mod tests {
-    use crate::{block::Block, transaction::Transaction};
-    use std::time::SystemTime;
+    use crate::{
+        block::{Block, BlockError, BlockHeader, BLOCK_VERSION},
+        pow::meets_difficulty,
+        transaction::{Transaction, TransactionError},
+    };
+    use libp2p::identity::Keypair;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::{Duration, SystemTime};
 
-    pub fn generate_blocks() -> Vec<Block> {
-        let time_now = SystemTime::now();
-
-        let tx1 = Transaction {
-            from: String::from("Alice"),
-            to: String::from("Bob"),
-            time: time_now,
-            amount: 32,
-        };
+    /// Mines at the current time, a millisecond after the last block this
+    /// mined at the earliest, so blocks mined one after another are always
+    /// timestamped after their parent.
+    pub fn mine(prev: &str, txs: Vec<Transaction>, difficulty: u32) -> Block {
+        static LAST: AtomicU64 = AtomicU64::new(0);
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let last = LAST
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| {
+                Some(now.max(last + 1))
+            })
+            .unwrap();
+        let time = SystemTime::UNIX_EPOCH + Duration::from_millis(now.max(last + 1));
+        mine_at(prev, txs, difficulty, time)
+    }
 
-        let new_block = Block {
-            hash: String::from("123"),
-            prev_hash: String::from("123"),
-            transactions: vec![tx1.clone()],
-            time: time_now,
-            nonce: 0,
+    /// Mines a block of `txs` behind a coinbase that claims nothing.
+    pub fn mine_at(prev: &str, txs: Vec<Transaction>, difficulty: u32, time: SystemTime) -> Block {
+        let mut txs = txs;
+        // The coinbase takes the block's time so that it is unique per block
+        let coinbase = Transaction {
+            time,
+            ..Transaction::coinbase(String::from("miner"), 0)
         };
+        txs.insert(0, coinbase);
+        (0..)
+            .find_map(|nonce| {
+                let mut block =
+                    Block::new(prev.to_string(), txs.clone(), difficulty, nonce, time);
+                block.generate_hash();
+                if meets_difficulty(&block.hash, difficulty) {
+                    Some(block)
+                } else {
+                    None
+                }
+            })
+            .unwrap()
+    }
 
-        let same_block = Block {
-            hash: String::from("123"),
-            prev_hash: String::from("123"),
-            transactions: vec![tx1.clone()],
-            time: time_now,
-            nonce: 0,
-        };
+    pub fn generate_blocks() -> Vec<Block> {
+        let alice = Keypair::generate_ed25519();
+        let tx1 = Transaction::new_signed(&alice, String::from("Bob"), 32, 1).unwrap();
 
-        let next_block = Block {
-            hash: String::from("new_hash"),
-            prev_hash: String::from("123"),
-            transactions: vec![tx1.clone()],
-            time: time_now,
-            nonce: 0,
-        };
+        let new_block = mine("123", vec![tx1.clone()], 4);
+        let same_block = mine(&new_block.hash, vec![tx1.clone()], 4);
+        let next_block = mine(&same_block.hash, vec![tx1], 4);
 
         vec![new_block, same_block, next_block]
     }
 
     #[test]
     fn test_block() {
-        let time_now = SystemTime::now();
-
-        let tx1 = Transaction {
-            from: String::from("Alice"),
-            to: String::from("Bob"),
-            time: time_now,
-            amount: 32,
-        };
+        let alice = Keypair::generate_ed25519();
+        let bob = Keypair::generate_ed25519();
+        let tx1 = Transaction::new_signed(&alice, String::from("Bob"), 32, 1).unwrap();
 
         let blocks = generate_blocks();
         let mut new_block = blocks[0].clone();
-        let next_block = blocks[2].clone();
-        let mut same_block = blocks[1].clone();
+        let next_block = blocks[1].clone();
+        let mut same_block = blocks[0].clone();
 
-        assert!(next_block.is_valid(&new_block));
+        assert!(next_block.validate(&new_block, 4).is_ok());
 
         let first_block_digest = new_block.generate_hash();
         assert_eq!(first_block_digest, same_block.generate_hash());
+        assert_eq!(first_block_digest, blocks[0].hash);
 
         let time_now2 = SystemTime::now();
-        let second_block_time_differ = Block {
-            hash: String::from("123"),
-            prev_hash: String::from("123"),
-            transactions: vec![tx1.clone()],
-            time: time_now2,
-            nonce: 0,
-        };
+        let second_block_time_differ =
+            Block::new(String::from("123"), vec![tx1.clone()], 4, 0, time_now2);
 
         assert_ne!(
             first_block_digest,
             second_block_time_differ.clone().generate_hash()
         );
 
-        let tx2 = Transaction {
-            from: String::from("Bob"),
-            to: String::from("Alice"),
-            time: time_now2,
-            amount: 32,
-        };
-        let second_block_txs_differ = Block {
-            hash: String::from("123"),
-            prev_hash: String::from("123"),
-            transactions: vec![tx1, tx2],
-            time: time_now2,
-            nonce: 0,
-        };
+        let tx2 = Transaction::new_signed(&bob, String::from("Alice"), 32, 1).unwrap();
+        let second_block_txs_differ =
+            Block::new(String::from("123"), vec![tx1, tx2], 4, 0, time_now2);
 
         assert_ne!(
             second_block_time_differ.clone().generate_hash(),
             second_block_txs_differ.clone().generate_hash()
         );
     }
+
+    #[test]
+    fn test_block_validation_errors() {
+        let blocks = generate_blocks();
+
+        assert!(matches!(
+            blocks[2].validate(&blocks[0], 4),
+            Err(BlockError::PrevHashMismatch { .. })
+        ));
+
+        let mut forged = blocks[1].clone();
+        forged.hash = String::from("0made-up-hash");
+        assert!(matches!(
+            forged.validate(&blocks[0], 4),
+            Err(BlockError::HashMismatch { .. })
+        ));
+
+        let mut tampered = blocks[1].clone();
+        tampered.header.nonce += 1;
+        assert!(matches!(
+            tampered.validate(&blocks[0], 4),
+            Err(BlockError::HashMismatch { .. })
+        ));
+
+        // Swapping transactions under a mined header breaks the merkle root
+        let mut swapped = blocks[1].clone();
+        swapped.transactions[0] = blocks[2].transactions[0].clone();
+        assert!(matches!(
+            swapped.validate(&blocks[0], 4),
+            Err(BlockError::MerkleRootMismatch { .. })
+        ));
+
+        assert!(matches!(
+            blocks[1].validate(&blocks[0], 5),
+            Err(BlockError::DifficultyMismatch { .. })
+        ));
+
+        let mut forged_tx = blocks[1].transactions[1].clone();
+        forged_tx.amount = 1000;
+        let resigned = mine(&blocks[0].hash, vec![forged_tx], 4);
+        assert_eq!(
+            resigned.validate(&blocks[0], 4),
+            Err(BlockError::InvalidTransaction {
+                index: 1,
+                reason: TransactionError::InvalidSignature
+            })
+        );
+
+        let second_coinbase = Transaction::coinbase(String::from("miner"), 0);
+        let double_paid = mine(&blocks[0].hash, vec![second_coinbase], 4);
+        assert_eq!(
+            double_paid.validate(&blocks[0], 4),
+            Err(BlockError::MisplacedCoinbase(1))
+        );
+
+        let mut no_coinbase = blocks[1].clone();
+        no_coinbase.transactions.remove(0);
+        no_coinbase.header.merkle_root = no_coinbase.calculate_merkle_root();
+        while !meets_difficulty(&no_coinbase.generate_hash(), 4) {
+            no_coinbase.header.nonce += 1;
+        }
+        assert_eq!(
+            no_coinbase.validate(&blocks[0], 4),
+            Err(BlockError::MissingCoinbase)
+        );
+
+        let mut lazy = blocks[1].clone();
+        while meets_difficulty(&lazy.generate_hash(), 4) {
+            lazy.header.nonce += 1;
+        }
+        assert!(matches!(
+            lazy.validate(&blocks[0], 4),
+            Err(BlockError::InsufficientWork { .. })
+        ));
+
+        let mut future = blocks[1].clone();
+        future.header.version = BLOCK_VERSION + 1;
+        future.generate_hash();
+        assert_eq!(
+            future.validate(&blocks[0], 4),
+            Err(BlockError::UnsupportedVersion(BLOCK_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn test_header_encoding() {
+        let header = BlockHeader {
+            version: 1,
+            prev_hash: String::from("ab"),
+            merkle_root: String::from("cd"),
+            timestamp: 0x0102,
+            difficulty: 3,
+            nonce: 4,
+        };
+        assert_eq!(
+            header.encode(),
+            vec![
+                1, 0, 0, 0, 2, 0, 0, 0, b'a', b'b', 2, 0, 0, 0, b'c', b'd', 2, 1, 0, 0, 0, 0, 0,
+                0, 3, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0,
+            ]
+        );
+        assert_eq!(header.hash(), "7a597d447f5ec4abc192b09ad05e014c6599a501c1ca22f73bd3bc3b15316f46");
+        assert_eq!(
+            header.time(),
+            SystemTime::UNIX_EPOCH + Duration::from_millis(0x0102)
+        );
+    }
+
+    #[test]
+    fn test_merkle_proof() {
+        let alice = Keypair::generate_ed25519();
+        let txs: Vec<Transaction> = (1..6)
+            .map(|amount| Transaction::new_signed(&alice, String::from("Bob"), amount, 1).unwrap())
+            .collect();
+        let block = mine("123", txs, 4);
+
+        for tx in &block.transactions {
+            let proof = block.merkle_proof(&tx.id()).unwrap();
+            assert!(proof.verify(&tx.id(), &block.header.merkle_root));
+        }
+
+        let other = generate_blocks();
+        let foreign_tx = other[1].transactions[1].id();
+        assert!(block.merkle_proof(&foreign_tx).is_none());
+
+        let proof = block.merkle_proof(&block.transactions[1].id()).unwrap();
+        assert!(!proof.verify(&foreign_tx, &block.header.merkle_root));
+        assert!(!proof.verify(&block.transactions[1].id(), &other[1].header.merkle_root));
+    }
 }
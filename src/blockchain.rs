@@ -1,123 +1,520 @@
-use crate::{block::Block, transaction::Transaction};
-use rand::Rng;
+use crate::{
+    block::{Block, BlockError, BlockHeader},
+    chain_spec::ChainSpec,
+    error::Error,
+    pow::{meets_difficulty, retarget, work},
+    state::{AccountState, PendingState},
+    transaction::Transaction,
+};
 use rayon::prelude::*;
-use serde::{Deserialize, Serialize};
 use std::cmp::PartialEq;
 use std::fmt;
-use std::time::SystemTime;
-
-#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
+
+/// Number of recent blocks whose fees `estimate_fee` looks at.
+pub const FEE_ESTIMATE_BLOCKS: usize = 10;
+/// Fee rate `estimate_fee` falls back to while recent blocks carry no fees.
+pub const MIN_FEE_RATE: u64 = 1;
+/// A block must be timestamped after the median of this many blocks before it.
+pub const MEDIAN_TIME_BLOCKS: usize = 11;
+/// How far ahead of the local clock a block may be timestamped.
+pub const MAX_FUTURE_DRIFT: Duration = Duration::from_secs(2 * 60 * 60);
+
+#[derive(PartialEq, Clone, Debug)]
 pub struct Blockchain {
+    /// Always starts with the genesis block of the chain spec
     pub chain: Vec<Block>,
-    difficulty: usize,
+    genesis_hash: String,
+    /// Proof-of-work target of the first blocks, as the number of leading
+    /// zero bits a hash must have. Later blocks follow `difficulty_at`.
+    difficulty: u32,
+    /// Difficulty is recomputed every `retarget_interval` blocks.
+    retarget_interval: usize,
+    target_block_time: Duration,
+    initial_reward: u64,
+    halving_interval: u64,
+    max_block_size: usize,
     concurrent_hashes: u64,
     min_tx_per_block: u8,
+    /// Balances at the tip, rebuilt from `chain` as blocks are added
+    state: AccountState,
 }
 
 impl Blockchain {
-    pub fn new(min_tx_per_block: u8, difficulty: usize, concurrent_hashes: u64) -> Self {
+    pub fn new(spec: &ChainSpec, min_tx_per_block: u8, concurrent_hashes: u64) -> Self {
+        let genesis = spec.genesis_block();
         Blockchain {
-            chain: vec![],
-            difficulty,
+            genesis_hash: genesis.hash.clone(),
+            state: AccountState::genesis(&genesis),
+            chain: vec![genesis],
+            difficulty: spec.difficulty,
+            // A window needs two blocks to measure a timespan
+            retarget_interval: spec.retarget_interval.max(2),
+            target_block_time: Duration::from_secs(spec.target_block_time),
+            initial_reward: spec.initial_reward,
+            // Zero would halve on every block
+            halving_interval: spec.halving_interval.max(1),
+            max_block_size: spec.max_block_size,
             concurrent_hashes,
             min_tx_per_block,
         }
     }
 
+    /// A chain of `chain` blocks under the same consensus parameters as `self`.
+    /// If the blocks overspend, the state is left empty; `validate` reports why.
+    pub fn with_blocks(&self, chain: Vec<Block>) -> Self {
+        Blockchain {
+            state: self.replay_state(&chain).unwrap_or_default(),
+            chain,
+            genesis_hash: self.genesis_hash.clone(),
+            difficulty: self.difficulty,
+            retarget_interval: self.retarget_interval,
+            target_block_time: self.target_block_time,
+            initial_reward: self.initial_reward,
+            halving_interval: self.halving_interval,
+            max_block_size: self.max_block_size,
+            concurrent_hashes: self.concurrent_hashes,
+            min_tx_per_block: self.min_tx_per_block,
+        }
+    }
+
+    pub fn genesis_hash(&self) -> &str {
+        &self.genesis_hash
+    }
+
     pub fn len(&self) -> usize {
         self.chain.len()
     }
 
-    pub fn add_block(&mut self, block: Block) {
-        if self.chain.len() == 0 {
-            return;
-        }
+    pub fn is_empty(&self) -> bool {
+        self.chain.is_empty()
+    }
 
-        let latest_block = self.chain.last().unwrap();
-        if block.is_valid(latest_block) {
-            self.chain.push(block);
-        } 
-        // Here in else we should add this block to orphans, but we will not do it
+    pub fn contains(&self, hash: &str) -> bool {
+        self.height_of(hash).is_some()
     }
 
-    pub fn is_valid(&self) -> bool {
-        for i in 1..self.chain.len() {
-            if !(self
-                .chain
-                .get(i)
-                .unwrap()
-                .is_valid(self.chain.get(i - 1).unwrap()))
-            {
-                return false;
+    pub fn height_of(&self, hash: &str) -> Option<usize> {
+        self.chain.iter().position(|block| block.hash == hash)
+    }
+
+    /// Hashes of the tip, then of blocks further and further back, ending at
+    /// genesis. A peer finds the last block we share with it in there.
+    pub fn locator(&self) -> Vec<String> {
+        let mut locator = vec![];
+        let mut height = self.chain.len();
+        let mut step = 1;
+        while height > 0 {
+            height = height.saturating_sub(step);
+            locator.push(self.chain[height].hash.clone());
+            if locator.len() >= 8 {
+                step *= 2;
             }
         }
+        locator
+    }
 
-        true
+    /// Up to `max` headers following the highest block of `locator` we have.
+    pub fn headers_after(&self, locator: &[String], max: usize) -> Vec<BlockHeader> {
+        let start = match locator.iter().find_map(|hash| self.height_of(hash)) {
+            Some(height) => height + 1,
+            None => return vec![],
+        };
+        self.chain
+            .iter()
+            .skip(start)
+            .take(max)
+            .map(|block| block.header.clone())
+            .collect()
     }
 
-    pub fn try_mine(&mut self, txs: Vec<Transaction>) -> bool {
-        let success;
-        if txs.len() < self.min_tx_per_block.into() {
-            println!(
-                "Not enough txs to mine block. Current txs {}, Current min is {}",
-                txs.len(),
-                self.min_tx_per_block
-            );
-            success = false;
+    /// The blocks among `hashes` that are on the chain, in the same order.
+    pub fn blocks_by_hash(&self, hashes: &[String]) -> Vec<Block> {
+        hashes
+            .iter()
+            .filter_map(|hash| self.height_of(hash).map(|height| self.chain[height].clone()))
+            .collect()
+    }
+
+    /// Balances after `chain`, checking only balances and coinbase amounts.
+    fn replay_state(&self, chain: &[Block]) -> Result<AccountState, BlockError> {
+        let mut state = match chain.first() {
+            Some(genesis) => AccountState::genesis(genesis),
+            None => return Ok(AccountState::default()),
+        };
+
+        for (height, block) in chain.iter().enumerate().skip(1) {
+            state.apply_block(block, self.subsidy_at(height))?;
+        }
+
+        Ok(state)
+    }
+
+    /// Newly minted coins the coinbase of the block at `height` may claim,
+    /// halving every `halving_interval` blocks.
+    pub fn subsidy_at(&self, height: usize) -> u64 {
+        let halvings = height as u64 / self.halving_interval;
+        if halvings >= 64 {
+            0
         } else {
-            let mut nonce = 0;
-            loop {
-                let time = SystemTime::now();
-
-                let block = self.mine_block(nonce, time, txs.clone());
-                match block {
-                    Some(block) => {
-                        self.chain.push(block);
-                        success = true;
-                        break;
-                    }
-                    None => {}
-                };
-
-                nonce += self.concurrent_hashes;
-            }
+            self.initial_reward >> halvings
+        }
+    }
+
+    /// Coinbase paying `miner` the next block's subsidy plus the fees of `txs`.
+    /// Its time is kept after the tip's coinbase, so that two blocks in the
+    /// same millisecond can not pay the same coinbase twice.
+    pub fn coinbase_for(&self, miner: &str, txs: &[Transaction]) -> Transaction {
+        let fees = txs.iter().fold(0u64, |fees, tx| fees.saturating_add(tx.fee));
+        let amount = self.subsidy_at(self.chain.len()).saturating_add(fees);
+        let coinbase = Transaction::coinbase(miner.to_string(), amount);
+        match self.chain.last().and_then(|tip| tip.transactions.first()) {
+            Some(last) if last.is_coinbase() && last.time >= coinbase.time => Transaction {
+                time: last.time + Duration::from_millis(1),
+                ..coinbase
+            },
+            _ => coinbase,
+        }
+    }
+
+    /// Fee for a transaction of `size` bytes at the median fee rate of the
+    /// last `FEE_ESTIMATE_BLOCKS` blocks, never less than `MIN_FEE_RATE`.
+    pub fn estimate_fee(&self, size: usize) -> u64 {
+        let mut rates: Vec<u64> = self
+            .chain
+            .iter()
+            .skip(1)
+            .rev()
+            .take(FEE_ESTIMATE_BLOCKS)
+            .flat_map(|block| block.transactions.iter().skip(1))
+            .map(Transaction::fee_rate)
+            .collect();
+        rates.sort_unstable();
+        let rate = rates
+            .get(rates.len() / 2)
+            .copied()
+            .unwrap_or(MIN_FEE_RATE)
+            .max(MIN_FEE_RATE);
+
+        let fee = (rate as u128 * size as u128).div_ceil(1000);
+        (fee as u64).max(1)
+    }
+
+    fn check_size(&self, block: &Block) -> Result<(), BlockError> {
+        let size = block.size();
+        if size > self.max_block_size {
+            return Err(BlockError::Oversized {
+                size,
+                limit: self.max_block_size,
+            });
         }
-        success
+        Ok(())
+    }
+
+    pub fn balance(&self, address: &str) -> u64 {
+        self.state.balance(address)
+    }
+
+    pub fn state(&self) -> &AccountState {
+        &self.state
+    }
+
+    pub fn tip_hash(&self) -> &str {
+        self.chain.last().map_or("", |block| block.hash.as_str())
     }
 
-    fn mine_block(
+    /// Expected number of hashes it took to produce every block in the chain.
+    pub fn total_work(&self) -> u128 {
+        self.chain.iter().map(|block| work(block.header.difficulty)).sum()
+    }
+
+    /// Difficulty the block at `height` must be mined at. It only changes on
+    /// retarget boundaries, from the timestamps of the preceding window, so
+    /// every node derives the same value from the same chain.
+    pub fn difficulty_at(&self, height: usize) -> u32 {
+        self.difficulty_with(height, |height| &self.chain[height].header)
+    }
+
+    /// `difficulty_at` over the headers `header_at` returns for each height
+    /// below `height`, which need not be on the chain yet.
+    fn difficulty_with<'a, F>(&self, height: usize, header_at: F) -> u32
+    where
+        F: Fn(usize) -> &'a BlockHeader,
+    {
+        if height == 0 {
+            return self.difficulty;
+        }
+
+        let prev = header_at(height - 1);
+        if !height.is_multiple_of(self.retarget_interval) {
+            return prev.difficulty;
+        }
+
+        let first = header_at(height - self.retarget_interval);
+        let actual = prev.timestamp.saturating_sub(first.timestamp) as u128;
+        let expected =
+            self.target_block_time.as_millis() * (self.retarget_interval as u128 - 1);
+
+        retarget(prev.difficulty, actual, expected)
+    }
+
+    /// Median timestamp of the `MEDIAN_TIME_BLOCKS` headers `header_at`
+    /// returns below `height`, the lower one of an even count.
+    fn median_time_with<'a, F>(&self, height: usize, header_at: F) -> u64
+    where
+        F: Fn(usize) -> &'a BlockHeader,
+    {
+        let mut times: Vec<u64> = (height.saturating_sub(MEDIAN_TIME_BLOCKS)..height)
+            .map(|height| header_at(height).timestamp)
+            .collect();
+        times.sort_unstable();
+        times[(times.len() - 1) / 2]
+    }
+
+    /// Checks that `header`, at `height`, is timestamped after the median of
+    /// the blocks before it and at most `MAX_FUTURE_DRIFT` ahead of our clock.
+    fn check_time_with<'a, F>(
         &self,
-        nonce: u64,
-        time: SystemTime,
-        txs: Vec<Transaction>
-    ) -> Option<Block> {
-        const CHARSET: &[u8] = b"abcdef\
-                            0123456789";
-        let mut rng = rand::thread_rng();
-
-        let mut mine_target: String = (0..self.difficulty)
-            .map(|_| {
-                let idx = rng.gen_range(0..CHARSET.len());
-                CHARSET[idx] as char
+        height: usize,
+        header: &BlockHeader,
+        header_at: F,
+    ) -> Result<(), BlockError>
+    where
+        F: Fn(usize) -> &'a BlockHeader,
+    {
+        let median = self.median_time_with(height, header_at);
+        if header.timestamp <= median {
+            return Err(BlockError::TimestampTooEarly {
+                timestamp: header.timestamp,
+                median,
+            });
+        }
+
+        let limit = millis(SystemTime::now() + MAX_FUTURE_DRIFT);
+        if header.timestamp > limit {
+            return Err(BlockError::TimestampInFuture {
+                timestamp: header.timestamp,
+                limit,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Checks `headers[from..]`, where `headers[i]` claims height
+    /// `fork_point + 1 + i` on top of `chain[..=fork_point]`: each must link
+    /// to the one before, be timestamped in time, carry the expected
+    /// difficulty and meet it.
+    pub fn check_headers(
+        &self,
+        fork_point: usize,
+        headers: &[BlockHeader],
+        from: usize,
+    ) -> Result<(), BlockError> {
+        let header_at = |height: usize| {
+            if height <= fork_point {
+                &self.chain[height].header
+            } else {
+                &headers[height - fork_point - 1]
+            }
+        };
+
+        for (i, header) in headers.iter().enumerate().skip(from) {
+            let height = fork_point + 1 + i;
+            let prev_hash = header_at(height - 1).hash();
+            if header.prev_hash != prev_hash {
+                return Err(BlockError::PrevHashMismatch {
+                    expected: prev_hash,
+                    found: header.prev_hash.clone(),
+                });
+            }
+
+            self.check_time_with(height, header, header_at)?;
+            let difficulty = self.difficulty_with(height, header_at);
+            if header.difficulty != difficulty {
+                return Err(BlockError::DifficultyMismatch {
+                    expected: difficulty,
+                    found: header.difficulty,
+                });
+            }
+
+            header.verify_work()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn next_difficulty(&self) -> u32 {
+        self.difficulty_at(self.chain.len())
+    }
+
+    pub fn add_block(&mut self, block: Block) -> Result<(), BlockError> {
+        let latest_block = match self.chain.last() {
+            Some(block) => block,
+            None => {
+                return Err(BlockError::GenesisMismatch {
+                    expected: self.genesis_hash.clone(),
+                    found: String::new(),
+                })
+            }
+        };
+
+        block.validate(latest_block, self.next_difficulty())?;
+        self.check_time_with(self.chain.len(), &block.header, |height| {
+            &self.chain[height].header
+        })?;
+        self.check_size(&block)?;
+        self.state.apply_block(&block, self.subsidy_at(self.chain.len()))?;
+        self.chain.push(block);
+        Ok(())
+    }
+
+    /// Checks every block from genesis, replaying balances from scratch.
+    pub fn validate(&self) -> Result<(), BlockError> {
+        let genesis = match self.chain.first() {
+            Some(genesis) if genesis.hash == self.genesis_hash => genesis,
+            other => {
+                return Err(BlockError::GenesisMismatch {
+                    expected: self.genesis_hash.clone(),
+                    found: other.map_or(String::new(), |block| block.hash.clone()),
+                })
+            }
+        };
+
+        let mut state = AccountState::genesis(genesis);
+        for (i, pair) in self.chain.windows(2).enumerate() {
+            pair[1].validate(&pair[0], self.difficulty_at(i + 1))?;
+            self.check_time_with(i + 1, &pair[1].header, |height| {
+                &self.chain[height].header
+            })?;
+            self.check_size(&pair[1])?;
+            state.apply_block(&pair[1], self.subsidy_at(i + 1))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.validate().is_ok()
+    }
+
+    /// Mines the transactions that still apply on top of the tip, paying the
+    /// block reward to `miner`. See `template` for which ones go in.
+    pub fn try_mine(&mut self, miner: &str, txs: Vec<Transaction>) -> Result<(), Error> {
+        let block = self
+            .template(miner, txs)?
+            .search(&AtomicBool::new(false))
+            .expect("search is never cancelled");
+        self.add_block(block)?;
+        Ok(())
+    }
+
+    /// The next block to mine on top of the tip, paying the block reward to
+    /// `miner`. The highest fee rates go first until the block is full. Ones
+    /// that overspend or were already mined are left out.
+    pub fn template(&self, miner: &str, txs: Vec<Transaction>) -> Result<BlockTemplate, Error> {
+        let mut txs = txs;
+        // Stable, so equal rates keep the order they were given in
+        txs.sort_by_key(|tx| std::cmp::Reverse(tx.fee_rate()));
+
+        let mut state = PendingState::new(&self.state);
+        let mut size = 0;
+        let mut skipped = vec![];
+        let mut txs: Vec<Transaction> = txs
+            .into_iter()
+            .filter(|tx| {
+                if size + tx.size() > self.max_block_size {
+                    return false;
+                }
+                if tx.verify().is_err() || state.apply_transaction(tx).is_err() {
+                    skipped.push(tx.clone());
+                    return false;
+                }
+                size += tx.size();
+                true
             })
             .collect();
 
-        mine_target = mine_target.to_lowercase();
+        // Make room for the coinbase, dropping the cheapest transactions. They
+        // were picked last, so nothing picked before depends on them.
+        let mut coinbase = self.coinbase_for(miner, &txs);
+        while size + coinbase.size() > self.max_block_size {
+            match txs.pop() {
+                Some(tx) => size -= tx.size(),
+                None => break,
+            }
+            coinbase = self.coinbase_for(miner, &txs);
+        }
+
+        if txs.len() < self.min_tx_per_block.into() {
+            return Err(Error::NotEnoughTransactions {
+                found: txs.len(),
+                min: self.min_tx_per_block.into(),
+            });
+        }
+
+        let mut block_txs = vec![coinbase];
+        block_txs.extend(txs);
+
+        // A clock behind the recent blocks still mines a valid block
+        let median = self.median_time_with(self.chain.len(), |height| &self.chain[height].header);
+        Ok(BlockTemplate {
+            prev_hash: self.tip_hash().to_string(),
+            difficulty: self.next_difficulty(),
+            earliest: SystemTime::UNIX_EPOCH + Duration::from_millis(median + 1),
+            transactions: block_txs,
+            skipped,
+            concurrent_hashes: self.concurrent_hashes,
+        })
+    }
+}
+
+/// Everything needed to mine a block, without the chain it goes on. Takes
+/// no locks, so the search can run on a thread of its own.
+#[derive(Clone, Debug)]
+pub struct BlockTemplate {
+    pub prev_hash: String,
+    pub difficulty: u32,
+    /// The block may not be timestamped before this
+    pub earliest: SystemTime,
+    /// Coinbase first
+    pub transactions: Vec<Transaction>,
+    /// Offered transactions left out because they do not apply to the tip
+    pub skipped: Vec<Transaction>,
+    concurrent_hashes: u64,
+}
+
+impl BlockTemplate {
+    /// Tries nonces until a block meets the difficulty, or returns `None`
+    /// once `cancel` is set.
+    pub fn search(&self, cancel: &AtomicBool) -> Option<Block> {
+        let mut nonce = 0;
+        while !cancel.load(Ordering::Relaxed) {
+            let time = SystemTime::now().max(self.earliest);
+
+            if let Some(block) = self.mine_block(nonce, time) {
+                return Some(block);
+            }
+
+            nonce += self.concurrent_hashes;
+        }
+        None
+    }
 
+    fn mine_block(&self, nonce: u64, time: SystemTime) -> Option<Block> {
+        let difficulty = self.difficulty;
         let nonces: Vec<u64> = (0..self.concurrent_hashes).map(|x| x + nonce).collect();
 
-        let prev = match self.chain.len() {
-            0 => String::new(),
-            _ => self.chain.get(self.chain.len() - 1).unwrap().hash.clone(),
-        };
+        let prev = &self.prev_hash;
+        let txs = &self.transactions;
 
         nonces.par_iter().find_map_any(move |&nonce| {
-            let mut block = Block::new(prev.clone(), txs.clone(), nonce, time);
+            let mut block = Block::new(prev.clone(), txs.clone(), difficulty, nonce, time);
 
             let hash = block.generate_hash();
 
-            if hash.starts_with(&mine_target) {
-                println!("\nMined! {}\n", block.hash.clone());
+            if meets_difficulty(&hash, difficulty) {
                 return Some(block);
             }
 
@@ -126,6 +523,11 @@ impl Blockchain {
     }
 }
 
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
 impl fmt::Display for Blockchain {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         // Write strictly the first element into the supplied output
@@ -140,80 +542,311 @@ impl fmt::Display for Blockchain {
             display_chain.push_str(&("-".repeat(15) + "\r\n"));
             display_chain.push_str(&self.chain[i].to_string());
         }
-        if self.chain.len() == 0 {
-            display_chain.push_str("Chain is empty for now. Try to generate few transactions");
+        if self.chain.len() == 1 {
+            display_chain.push_str("Chain has only the genesis block for now. Try to generate few transactions");
         }
-        write!(f, "{}\n", display_chain)
+        writeln!(f, "{}", display_chain)
     }
 }
 
 #[cfg(test)]
 pub mod tests {
-    use crate::block::tests::generate_blocks;
-    use crate::{blockchain::Blockchain, transaction::Transaction};
-    use std::time::SystemTime;
+    use crate::block::tests::{generate_blocks, mine, mine_at};
+    use crate::{
+        block::{Block, BlockError},
+        blockchain::{BlockTemplate, Blockchain, MAX_FUTURE_DRIFT},
+        chain_spec::ChainSpec,
+        error::Error,
+        transaction::Transaction,
+    };
+    use libp2p::{identity::Keypair, PeerId};
+    use std::collections::BTreeMap;
+    use std::time::{Duration, SystemTime};
+
+    pub fn test_spec() -> ChainSpec {
+        ChainSpec {
+            chain_id: String::from("echain-test"),
+            difficulty: 4,
+            timestamp: 1_000_000,
+            retarget_interval: 10,
+            target_block_time: 30,
+            initial_reward: 50,
+            halving_interval: 100,
+            max_block_size: 100_000,
+            allocations: BTreeMap::new(),
+        }
+    }
 
-    pub fn generate_blockchain() -> Blockchain {
-        let blocks = generate_blocks();
-        let chain = Blockchain {
-            chain: blocks,
-            difficulty: 5,
-            min_tx_per_block: 3,
-            concurrent_hashes: 256,
-        };
+    /// `test_spec` with `amount` allocated to the address of `owner`.
+    pub fn funded_spec(owner: &Keypair, amount: u64) -> ChainSpec {
+        let mut spec = test_spec();
+        spec.allocations
+            .insert(PeerId::from(owner.public()).to_string(), amount);
+        spec
+    }
 
+    pub fn generate_blockchain() -> Blockchain {
+        let mut chain = Blockchain::new(&test_spec(), 3, 256);
+        for _ in 0..2 {
+            let block = mine(chain.tip_hash(), vec![], chain.next_difficulty());
+            chain.add_block(block).unwrap();
+        }
         chain
     }
 
     #[test]
     fn test_chain_validity() {
-        let blocks = crate::block::tests::generate_blocks();
+        let chain = generate_blockchain();
+        assert_eq!(chain.chain[0], test_spec().genesis_block());
+        assert!(chain.is_valid());
 
-        let chain = Blockchain {
-            chain: blocks,
-            difficulty: 1,
-            min_tx_per_block: 1,
-            concurrent_hashes: 256,
+        let foreign = chain.with_blocks(generate_blocks());
+        assert!(matches!(
+            foreign.validate(),
+            Err(BlockError::GenesisMismatch { .. })
+        ));
+
+        let other_spec = ChainSpec {
+            chain_id: String::from("echain-other"),
+            ..test_spec()
         };
+        let mut other = Blockchain::new(&other_spec, 3, 256);
+        assert!(matches!(
+            other.add_block(mine(chain.tip_hash(), vec![], 4)),
+            Err(BlockError::PrevHashMismatch { .. })
+        ));
+        assert!(!chain.with_blocks(other.chain).is_valid());
+    }
 
+    #[test]
+    fn test_add_block() {
+        let mut chain = generate_blockchain();
+        let tip = chain.chain.last().unwrap().hash.clone();
+
+        let forged = Block {
+            hash: String::from("0made-up-hash"),
+            ..Block::new(tip.clone(), vec![], 4, 0, SystemTime::now())
+        };
+        assert!(matches!(
+            chain.add_block(forged),
+            Err(BlockError::HashMismatch { .. })
+        ));
+        assert_eq!(chain.len(), 3);
+
+        assert!(matches!(
+            chain.add_block(mine(&tip, vec![], 1)),
+            Err(BlockError::DifficultyMismatch { .. })
+        ));
+        assert!(chain.add_block(mine(&tip, vec![], 4)).is_ok());
+        assert_eq!(chain.len(), 4);
         assert!(chain.is_valid());
     }
 
     #[test]
-    fn test_display() {
-        let blocks = crate::block::tests::generate_blocks();
+    fn test_difficulty_retarget() {
+        let mine_spaced = |spacing: u64| {
+            let spec = ChainSpec {
+                retarget_interval: 4,
+                target_block_time: 10,
+                ..test_spec()
+            };
+            let mut chain = Blockchain::new(&spec, 0, 256);
+            let start = spec.genesis_time();
+
+            for i in 1..8 {
+                let prev = chain.chain.last().unwrap().hash.clone();
+                let time = start + Duration::from_secs(spacing * i);
+                let block = mine_at(&prev, vec![], chain.next_difficulty(), time);
+                chain.add_block(block).unwrap();
+            }
+            chain
+        };
+
+        let on_target = mine_spaced(10);
+        assert_eq!(on_target.difficulty_at(4), 4);
+        assert_eq!(on_target.next_difficulty(), 4);
+
+        let too_fast = mine_spaced(4);
+        assert_eq!(too_fast.difficulty_at(3), 4);
+        assert_eq!(too_fast.difficulty_at(4), 5);
+        assert_eq!(too_fast.chain[5].header.difficulty, 5);
+        assert_eq!(too_fast.next_difficulty(), 6);
+
+        let too_slow = mine_spaced(100);
+        assert_eq!(too_slow.difficulty_at(4), 2);
+        assert_eq!(too_slow.next_difficulty(), 1);
+
+        // A block that ignores the retarget is rejected
+        let mut chain = mine_spaced(4);
+        let tip = chain.chain.last().unwrap().hash.clone();
+        assert!(matches!(
+            chain.add_block(mine(&tip, vec![], 5)),
+            Err(BlockError::DifficultyMismatch { .. })
+        ));
+        assert!(chain.is_valid());
+    }
 
-        let chain = Blockchain {
-            chain: blocks,
-            difficulty: 1,
-            min_tx_per_block: 1,
-            concurrent_hashes: 256,
+    #[test]
+    fn test_block_timestamps() {
+        let spec = ChainSpec {
+            retarget_interval: 4,
+            ..test_spec()
         };
+        let mut chain = Blockchain::new(&spec, 0, 256);
+        let start = spec.genesis_time();
+        for i in 1..4 {
+            let time = start + Duration::from_secs(10 * i);
+            let block = mine_at(chain.tip_hash(), vec![], chain.next_difficulty(), time);
+            chain.add_block(block).unwrap();
+        }
+
+        // Not after the median of the blocks before it
+        let tip = chain.tip_hash().to_string();
+        let difficulty = chain.next_difficulty();
+        let early = mine_at(&tip, vec![], difficulty, start + Duration::from_secs(10));
+        assert!(matches!(
+            chain.add_block(early),
+            Err(BlockError::TimestampTooEarly { .. })
+        ));
+        let late = SystemTime::now() + MAX_FUTURE_DRIFT + Duration::from_secs(60);
+        assert!(matches!(
+            chain.add_block(mine_at(&tip, vec![], difficulty, late)),
+            Err(BlockError::TimestampInFuture { .. })
+        ));
+        let headers = vec![mine_at(&tip, vec![], difficulty, late).header];
+        assert!(matches!(
+            chain.check_headers(3, &headers, 0),
+            Err(BlockError::TimestampInFuture { .. })
+        ));
+
+        // A forged timestamp at the end of a window does not break retargeting
+        let mut forged = chain.clone();
+        let prev = forged.chain[2].hash.clone();
+        forged.chain[3] = Block::new(prev, vec![], 4, 0, SystemTime::UNIX_EPOCH);
+        forged.chain[3].header.timestamp = u64::MAX;
+        assert_eq!(forged.difficulty_at(4), 2);
+    }
+
+    #[test]
+    fn test_subsidy_halving() {
+        let spec = ChainSpec {
+            halving_interval: 10,
+            ..test_spec()
+        };
+        let chain = Blockchain::new(&spec, 0, 256);
+        assert_eq!(chain.subsidy_at(1), 50);
+        assert_eq!(chain.subsidy_at(9), 50);
+        assert_eq!(chain.subsidy_at(10), 25);
+        assert_eq!(chain.subsidy_at(25), 12);
+        assert_eq!(chain.subsidy_at(60), 0);
+        assert_eq!(chain.subsidy_at(10_000), 0);
+    }
+
+    #[test]
+    fn test_try_mine_pays_miner() {
+        let alice = Keypair::generate_ed25519();
+        let alice_addr = PeerId::from(alice.public()).to_string();
+        let spec = funded_spec(&alice, 100);
+        let mut chain = Blockchain::new(&spec, 1, 256);
+
+        let pay_bob = Transaction::new_signed(&alice, String::from("Bob"), 10, 2).unwrap();
+        let overspend = Transaction::new_signed(&alice, String::from("Bob"), 1000, 2).unwrap();
+        let template = chain
+            .template("Miner", vec![pay_bob.clone(), overspend.clone()])
+            .unwrap();
+        assert_eq!(template.skipped, vec![overspend.clone()]);
+        chain.try_mine("Miner", vec![pay_bob.clone(), overspend]).unwrap();
+
+        let block = chain.chain.last().unwrap();
+        assert_eq!(block.transactions.len(), 2);
+        assert!(block.transactions[0].is_coinbase());
+        assert_eq!(block.transactions[1], pay_bob);
+        assert_eq!(chain.balance("Miner"), 52);
+        assert_eq!(chain.balance(&alice_addr), 88);
+        assert_eq!(chain.balance("Bob"), 10);
+
+        // Coinbases stay apart even when minted within one millisecond
+        let coinbase = chain.coinbase_for("Miner", &[]);
+        assert!(coinbase.time > block.transactions[0].time);
+        assert_ne!(coinbase.id(), block.transactions[0].id());
+
+        // Already mined, so nothing is left to fill the block with
+        assert!(matches!(
+            chain.try_mine("Miner", vec![pay_bob]),
+            Err(Error::NotEnoughTransactions { found: 0, min: 1 })
+        ));
+        assert!(chain.is_valid());
+    }
+
+    #[test]
+    fn test_fee_priority() {
+        let alice = Keypair::generate_ed25519();
+        let mut spec = funded_spec(&alice, 100);
+
+        let txs: Vec<Transaction> = [1, 9, 5]
+            .iter()
+            .map(|&fee| Transaction::new_signed(&alice, String::from("Bob"), 10, fee).unwrap())
+            .collect();
+        let coinbase = Transaction::coinbase(String::from("Miner"), 1000);
+        // Room for the coinbase and two of the three transactions
+        spec.max_block_size = coinbase.size() + 2 * txs[0].size() + 20;
+
+        let mut chain = Blockchain::new(&spec, 1, 256);
+        assert_eq!(chain.estimate_fee(1000), 1);
+        chain.try_mine("Miner", txs.clone()).unwrap();
+
+        let block = chain.chain.last().unwrap();
+        assert_eq!(block.transactions[1..].to_vec(), vec![txs[1].clone(), txs[2].clone()]);
+        assert_eq!(chain.balance("Miner"), 50 + 9 + 5);
+
+        // The median of the two fee rates mined is the higher one
+        let size = txs[0].size();
+        assert_eq!(
+            chain.estimate_fee(size),
+            (txs[1].fee_rate() * size as u64).div_ceil(1000)
+        );
+
+        let oversized = mine(chain.tip_hash(), txs[..1].to_vec(), chain.next_difficulty());
+        let mut tiny = chain.with_blocks(chain.chain.clone());
+        tiny.max_block_size = oversized.size() - 1;
+        assert!(matches!(
+            tiny.add_block(oversized),
+            Err(BlockError::Oversized { .. })
+        ));
+    }
+
+    #[test]
+    fn test_display() {
+        let chain = generate_blockchain();
 
         println!("{}", chain);
     }
     #[test]
     fn test_mining() {
+        let keypair = Keypair::generate_ed25519();
         let mut txs: Vec<Transaction> = vec![];
         for i in 0..10 {
-            txs.push(Transaction {
-                from: String::from("test"),
-                to: String::from(i.to_string()),
-                amount: i,
-                time: SystemTime::now(),
-            });
+            txs.push(Transaction::new_signed(&keypair, i.to_string(), i, 1).unwrap());
         }
 
         let concurrent_hashes = 256;
-        let chain = Blockchain::new(5, 3, concurrent_hashes);
+        let chain = Blockchain::new(&test_spec(), 5, concurrent_hashes);
         let mut _nonce = 0;
         let time = SystemTime::now();
+        let template = BlockTemplate {
+            prev_hash: chain.tip_hash().to_string(),
+            difficulty: chain.next_difficulty(),
+            earliest: time,
+            transactions: txs,
+            skipped: vec![],
+            concurrent_hashes,
+        };
 
         let mut cntr = 0;
         loop {
             cntr += 1;
 
-            chain.mine_block(1, time, txs.clone());
+            template.mine_block(1, time);
             _nonce += concurrent_hashes;
             if cntr == 100 {
                 break;
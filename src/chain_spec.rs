@@ -0,0 +1,205 @@
+use derive_more::{Display, From};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use std::{fs, io};
+
+use crate::{
+    block::{encode_str, Block},
+    transaction::Transaction,
+};
+
+/// Sender of the genesis allocation transactions.
+pub const GENESIS_ADDRESS: &str = "genesis";
+
+const DEV_SPEC: &str = include_str!("../specs/dev.json");
+
+#[derive(Debug, Display, From)]
+pub enum ChainSpecError {
+    #[display(fmt = "can not read chain spec: {}", _0)]
+    Io(io::Error),
+    #[display(fmt = "can not parse chain spec: {}", _0)]
+    Parse(serde_json::Error),
+}
+
+impl std::error::Error for ChainSpecError {}
+
+/// Everything a node needs to build the same genesis block and apply the same
+/// consensus rules as its peers.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ChainSpec {
+    pub chain_id: String,
+    /// Leading zero bits required of the first blocks after genesis
+    pub difficulty: u32,
+    /// Genesis time in seconds since the unix epoch
+    pub timestamp: u64,
+    #[serde(default = "default_retarget_interval")]
+    pub retarget_interval: usize,
+    /// Target block time in seconds
+    #[serde(default = "default_target_block_time")]
+    pub target_block_time: u64,
+    /// Coinbase subsidy of the first blocks, before any halving
+    #[serde(default = "default_initial_reward")]
+    pub initial_reward: u64,
+    /// The subsidy halves every `halving_interval` blocks
+    #[serde(default = "default_halving_interval")]
+    pub halving_interval: u64,
+    /// Most bytes the serialized transactions of a block may take up
+    #[serde(default = "default_max_block_size")]
+    pub max_block_size: usize,
+    /// Coins credited to each address by the genesis block
+    #[serde(default)]
+    pub allocations: BTreeMap<String, u64>,
+}
+
+fn default_retarget_interval() -> usize {
+    10
+}
+
+fn default_target_block_time() -> u64 {
+    30
+}
+
+fn default_initial_reward() -> u64 {
+    50
+}
+
+fn default_halving_interval() -> u64 {
+    100
+}
+
+fn default_max_block_size() -> usize {
+    100_000
+}
+
+impl ChainSpec {
+    /// The development chain shipped in `specs/dev.json`.
+    pub fn dev() -> Self {
+        serde_json::from_str(DEV_SPEC).expect("bundled dev spec is valid")
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, ChainSpecError> {
+        let json = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    pub fn genesis_time(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(self.timestamp)
+    }
+
+    /// Every field of the spec in declaration order, encoded like
+    /// `BlockHeader::encode`: integers as little-endian `u64` and strings with
+    /// a little-endian `u32` length prefix, the allocations behind their count.
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        encode_str(&mut bytes, &self.chain_id);
+        bytes.extend_from_slice(&u64::from(self.difficulty).to_le_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_le_bytes());
+        bytes.extend_from_slice(&(self.retarget_interval as u64).to_le_bytes());
+        bytes.extend_from_slice(&self.target_block_time.to_le_bytes());
+        bytes.extend_from_slice(&self.initial_reward.to_le_bytes());
+        bytes.extend_from_slice(&self.halving_interval.to_le_bytes());
+        bytes.extend_from_slice(&(self.max_block_size as u64).to_le_bytes());
+        bytes.extend_from_slice(&(self.allocations.len() as u64).to_le_bytes());
+        for (address, amount) in &self.allocations {
+            encode_str(&mut bytes, address);
+            bytes.extend_from_slice(&amount.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Builds the genesis block. It is not mined and its allocations are not
+    /// signed: it is trusted because every node derives the same one from the
+    /// spec. Its `prev_hash` is the hash of the whole spec, so nodes whose
+    /// consensus rules differ in any way get different genesis hashes.
+    pub fn genesis_block(&self) -> Block {
+        let time = self.genesis_time();
+        let allocations = self
+            .allocations
+            .iter()
+            .map(|(address, amount)| Transaction {
+                from: String::from(GENESIS_ADDRESS),
+                to: address.clone(),
+                time,
+                amount: *amount,
+                fee: 0,
+                public_key: vec![],
+                signature: vec![],
+            })
+            .collect();
+        let spec_hash = format!("{:x}", Sha256::digest(self.encode()));
+
+        let mut genesis = Block::new(spec_hash, allocations, self.difficulty, 0, time);
+        genesis.generate_hash();
+        genesis
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chain_spec::ChainSpec;
+
+    #[test]
+    fn test_genesis_is_deterministic() {
+        let spec = ChainSpec::dev();
+        assert_eq!(spec.genesis_block(), spec.genesis_block());
+        assert_eq!(spec.genesis_block().header.time(), spec.genesis_time());
+
+        let other_id = ChainSpec {
+            chain_id: String::from("echain-test"),
+            ..spec.clone()
+        };
+        assert_ne!(spec.genesis_block().hash, other_id.genesis_block().hash);
+
+        // Rules the genesis block itself does not use still change its hash
+        let other_rules = [
+            ChainSpec {
+                retarget_interval: spec.retarget_interval + 1,
+                ..spec.clone()
+            },
+            ChainSpec {
+                target_block_time: spec.target_block_time + 1,
+                ..spec.clone()
+            },
+            ChainSpec {
+                initial_reward: spec.initial_reward + 1,
+                ..spec.clone()
+            },
+            ChainSpec {
+                halving_interval: spec.halving_interval + 1,
+                ..spec.clone()
+            },
+            ChainSpec {
+                max_block_size: spec.max_block_size + 1,
+                ..spec.clone()
+            },
+        ];
+        for other in &other_rules {
+            assert_ne!(spec.genesis_block().hash, other.genesis_block().hash);
+        }
+
+        let mut funded = spec.clone();
+        funded.allocations.insert(String::from("Alice"), 1000);
+        let genesis = funded.genesis_block();
+        assert_ne!(spec.genesis_block().hash, genesis.hash);
+        assert_eq!(genesis.transactions.len(), 1);
+        assert_eq!(genesis.transactions[0].to, "Alice");
+        assert_eq!(genesis.transactions[0].amount, 1000);
+    }
+
+    #[test]
+    fn test_parse_spec() {
+        let spec: ChainSpec = serde_json::from_str(
+            r#"{"chain_id": "test", "difficulty": 4, "timestamp": 0, "allocations": {"Bob": 5}}"#,
+        )
+        .unwrap();
+        assert_eq!(spec.retarget_interval, 10);
+        assert_eq!(spec.target_block_time, 30);
+        assert_eq!(spec.initial_reward, 50);
+        assert_eq!(spec.halving_interval, 100);
+        assert_eq!(spec.max_block_size, 100_000);
+        assert_eq!(spec.allocations["Bob"], 5);
+    }
+}
@@ -0,0 +1,40 @@
+use derive_more::{Display, From};
+use libp2p::identity::error::SigningError;
+use std::io;
+
+use crate::{
+    block::BlockError, chain_spec::ChainSpecError, keystore::IdentityError, mempool::MempoolError,
+    p2p::ConfigError, protocol::ProtocolError, rpc::RpcError, storage::StorageError,
+    transaction::TransactionError,
+};
+
+/// Any error of the crate. Module errors convert into it with `?`; the
+/// variants below them are the failures that belong to no single module.
+#[derive(Debug, Display, From)]
+pub enum Error {
+    Block(BlockError),
+    Transaction(TransactionError),
+    Mempool(MempoolError),
+    Storage(StorageError),
+    Protocol(ProtocolError),
+    ChainSpec(ChainSpecError),
+    Identity(IdentityError),
+    Config(ConfigError),
+    #[display(fmt = "can not sign: {}", _0)]
+    Signing(SigningError),
+    Io(io::Error),
+    Rpc(RpcError),
+    #[display(fmt = "can not set up networking: {}", _0)]
+    #[from(ignore)]
+    Network(String),
+    #[display(fmt = "{} txs fit in the block but at least {} are needed", found, min)]
+    NotEnoughTransactions { found: usize, min: usize },
+    #[display(fmt = "local chain is invalid ({}) and so is the other ({})", local, other)]
+    InvalidChains { local: BlockError, other: BlockError },
+    /// A message of a kind that has no business where it was received
+    #[display(fmt = "unexpected {} message", _0)]
+    #[from(ignore)]
+    UnexpectedMessage(&'static str),
+}
+
+impl std::error::Error for Error {}
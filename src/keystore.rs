@@ -0,0 +1,151 @@
+use derive_more::{Display, From};
+use libp2p::identity::{ed25519, error::DecodingError, Keypair};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+const KEY_FILE: &str = "identity.key";
+
+#[derive(Debug, Display, From)]
+pub enum IdentityError {
+    #[display(fmt = "can not access key file: {}", _0)]
+    Io(io::Error),
+    #[display(fmt = "can not decode key file: {}", _0)]
+    Decode(DecodingError),
+}
+
+impl std::error::Error for IdentityError {}
+
+/// The node's ed25519 keypair, kept in `dir` so the peer id, and with it the
+/// wallet, survives restarts. A new keypair is generated and written on
+/// first run, readable by the owner only.
+pub fn load_or_create<P: AsRef<Path>>(dir: P) -> Result<Keypair, IdentityError> {
+    let path = dir.as_ref().join(KEY_FILE);
+    match fs::read(&path) {
+        Ok(mut bytes) => {
+            warn_if_exposed(&path);
+            let keypair = ed25519::Keypair::decode(&mut bytes)?;
+            Ok(Keypair::Ed25519(keypair))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let keypair = ed25519::Keypair::generate();
+            fs::create_dir_all(&dir)?;
+            write_private(&path, &keypair.encode())?;
+            Ok(Keypair::Ed25519(keypair))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Writes `bytes` to `path`, readable by the owner only. They are written
+/// aside and renamed, so a crash never leaves half a file. The permissions
+/// of a file left aside by an earlier crash are reset, as the mode given to
+/// `open` only applies to files it creates.
+pub(crate) fn write_private(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = restricted_options().open(&tmp)?;
+    restrict(&file)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+#[cfg(unix)]
+fn restricted_options() -> OpenOptions {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true).mode(0o600);
+    options
+}
+
+#[cfg(not(unix))]
+fn restricted_options() -> OpenOptions {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    options
+}
+
+#[cfg(unix)]
+fn restrict(file: &File) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    file.set_permissions(fs::Permissions::from_mode(0o600))
+}
+
+#[cfg(not(unix))]
+fn restrict(_file: &File) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+pub(crate) fn warn_if_exposed(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Ok(metadata) = fs::metadata(path) {
+        if metadata.permissions().mode() & 0o077 != 0 {
+            log::warn!("key file {} is readable by other users", path.display());
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn warn_if_exposed(_path: &Path) {}
+
+#[cfg(test)]
+mod tests {
+    use crate::keystore::{load_or_create, write_private, IdentityError, KEY_FILE};
+    use crate::storage::tests::temp_dir;
+    use libp2p::PeerId;
+    use std::fs;
+
+    #[test]
+    fn test_identity_survives_restart() {
+        let dir = temp_dir("identity");
+
+        let first = load_or_create(&dir).unwrap();
+        let second = load_or_create(&dir).unwrap();
+        assert_eq!(
+            PeerId::from(first.public()),
+            PeerId::from(second.public())
+        );
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(dir.join(KEY_FILE)).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        fs::write(dir.join(KEY_FILE), b"not a key").unwrap();
+        assert!(matches!(
+            load_or_create(&dir),
+            Err(IdentityError::Decode(_))
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_private_resets_leftover() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("write-private");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("secret");
+
+        // Left aside by a crash, with whatever mode the umask gave it
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, b"stale").unwrap();
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_private(&path, b"secret").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"secret");
+        assert!(!tmp.exists());
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
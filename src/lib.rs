@@ -0,0 +1,39 @@
+//! A small proof-of-work blockchain with a p2p network around it.
+//!
+//! `Blockchain` holds and validates the chain, `Node` ties it to the
+//! mempool, the orphan pool and the `BlockStore` on disk, and `p2p` gossips
+//! blocks and transactions between nodes and syncs their chains. The
+//! `echain` binary runs a node on top of this crate, with an interactive
+//! menu or controlled over `rpc`.
+
+pub mod block;
+pub mod blockchain;
+pub mod chain_spec;
+pub mod error;
+pub mod keystore;
+pub mod mempool;
+pub mod merkle;
+pub mod miner;
+pub mod node;
+pub mod orphans;
+pub mod p2p;
+pub mod pow;
+pub mod protocol;
+pub mod reputation;
+pub mod rpc;
+pub mod state;
+pub mod storage;
+pub mod sync;
+pub mod transaction;
+
+pub use block::{Block, BlockError, BlockHeader};
+pub use blockchain::Blockchain;
+pub use chain_spec::ChainSpec;
+pub use error::Error;
+pub use mempool::{Mempool, MempoolError};
+pub use node::Node;
+pub use p2p::{AppBehaviour, NetworkConfig};
+pub use protocol::Message;
+pub use state::AccountState;
+pub use storage::BlockStore;
+pub use transaction::Transaction;
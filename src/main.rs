@@ -1,243 +1,465 @@
-mod block;
-mod blockchain;
-mod node;
-mod p2p;
-mod transaction;
-
-use blockchain::Blockchain;
-use dialoguer::{theme::ColorfulTheme, Select};
-use tokio::sync::mpsc::{UnboundedSender};
-use p2p::{AppBehaviour, ChainResponse};
-use rand::seq::SliceRandom;
-use std::time::{Duration, SystemTime};
-use std::{
-    thread,
+use clap::{Parser, Subcommand};
+use dialoguer::{theme::ColorfulTheme, Input, Select};
+use echain::{
+    keystore, miner::MiningJob, node::BlockOutcome, p2p, rpc, Block, BlockStore, Blockchain,
+    ChainSpec, Error, NetworkConfig, Node, Transaction,
 };
-use transaction::Transaction;
+use rand::seq::SliceRandom;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use libp2p::{
-    core::upgrade,
     futures::StreamExt,
-    identity, mplex,
-    noise::{Keypair, NoiseConfig, X25519Spec},
-    swarm::{Swarm, SwarmBuilder},
-    tcp::TokioTcpConfig,
-    PeerId, Transport,
-};
-use tokio::{
-    select, spawn,
-    sync::{mpsc},
+    swarm::{Swarm, SwarmEvent},
 };
+use tokio::{net::TcpListener, select, spawn, sync::mpsc, time};
+
+/// Where the node keeps its chain unless `ECHAIN_DATA_DIR` says otherwise.
+const DEFAULT_DATA_DIR: &str = ".echain";
+
+/// Without a subcommand, runs a node with an interactive menu.
+#[derive(Parser)]
+#[command(name = "echain", version, about = "A simple proof-of-work blockchain")]
+struct Cli {
+    /// Address of the node's RPC server
+    #[arg(long, global = true, default_value = rpc::DEFAULT_RPC_ADDR)]
+    rpc_addr: SocketAddr,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(subcommand)]
+    Node(NodeCommand),
+    #[command(subcommand)]
+    Tx(TxCommand),
+    #[command(subcommand)]
+    Chain(ChainCommand),
+    #[command(subcommand)]
+    Peers(PeersCommand),
+    #[command(subcommand)]
+    Mempool(MempoolCommand),
+    /// Mine the pending transactions on the running node
+    Mine,
+}
+
+#[derive(Subcommand)]
+enum NodeCommand {
+    /// Run a node without the menu, controlled over RPC
+    Run,
+}
+
+#[derive(Subcommand)]
+enum TxCommand {
+    /// Send coins from the wallet of the running node
+    Send {
+        /// Address to pay
+        #[arg(long)]
+        to: String,
+        #[arg(long)]
+        amount: u64,
+        /// Fee to pay, estimated from recent blocks if left out
+        #[arg(long)]
+        fee: Option<u64>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ChainCommand {
+    /// Print every block of the chain
+    Show,
+    /// Check the chain from genesis, exiting with 1 if it is invalid
+    Verify,
+}
+
+#[derive(Subcommand)]
+enum PeersCommand {
+    /// Print the peers the node knows of
+    List,
+}
+
+#[derive(Subcommand)]
+enum MempoolCommand {
+    /// Print the transactions waiting to be mined
+    List,
+}
 
 pub fn handle_print_chain(chain: &Blockchain) {
     println!("{}", chain);
 }
 
-pub async fn swarm_factory(
-    node: node::Node,
-    rsp_sender: UnboundedSender<ChainResponse>,
-    ) -> SwarmBuilder<AppBehaviour> {
-    let id_keys = identity::Keypair::generate_ed25519();
-    let peer_id = PeerId::from(id_keys.public());
-
-    let auth_keys = Keypair::<X25519Spec>::new()
-        .into_authentic(&id_keys)
-        .expect("can create auth keys");
+/// Prints what the library logs the way the node prints everything else.
+struct Printer;
 
-    let transp = TokioTcpConfig::new()
-        .upgrade(upgrade::Version::V1)
-        .authenticate(NoiseConfig::xx(auth_keys).into_authenticated())
-        .multiplex(mplex::MplexConfig::new())
-        .boxed();
+static PRINTER: Printer = Printer;
 
-    let behaviour =
-        p2p::AppBehaviour::new(peer_id, node, rsp_sender).await;
+impl log::Log for Printer {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Info && metadata.target().starts_with("echain")
+    }
 
-    let swarm = SwarmBuilder::new(transp, behaviour, peer_id).executor(Box::new(|fut| {
-        spawn(fut);
-    }));
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            println!("{} \r\n", record.args());
+        }
+    }
 
-    swarm
+    fn flush(&self) {}
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> Result<(), Error> {
+    log::set_logger(&PRINTER).expect("no logger is set yet");
+    log::set_max_level(log::LevelFilter::Info);
+    let cli = Cli::parse();
+    match cli.command {
+        None => run_node(cli.rpc_addr, true).await,
+        Some(Command::Node(NodeCommand::Run)) => run_node(cli.rpc_addr, false).await,
+        Some(command) => run_client(cli.rpc_addr, command).await,
+    }
+}
+
+fn data_dir() -> String {
+    std::env::var("ECHAIN_DATA_DIR").unwrap_or_else(|_| String::from(DEFAULT_DATA_DIR))
+}
+
+/// `ECHAIN_RPC_TOKEN` if set, else the token kept in the data directory.
+/// Only a node creates one there, clients fail without it.
+fn rpc_token(data_dir: &str, create: bool) -> Result<String, Error> {
+    match std::env::var("ECHAIN_RPC_TOKEN") {
+        Ok(token) if !token.is_empty() => Ok(token),
+        _ if create => Ok(rpc::load_or_create_token(data_dir)?),
+        _ => Ok(rpc::load_token(data_dir)?),
+    }
+}
+
+/// Runs a subcommand against the node at `rpc_addr`.
+async fn run_client(rpc_addr: SocketAddr, command: Command) -> Result<(), Error> {
+    let token = rpc_token(&data_dir(), false)?;
+    match command {
+        Command::Tx(TxCommand::Send { to, amount, fee }) => {
+            let params = json!({ "to": to, "amount": amount, "fee": fee });
+            let sent = rpc::call(rpc_addr, &token, "tx_send", params).await?;
+            println!("Sent tx {} with fee {}", sent["id"], sent["fee"]);
+        }
+        Command::Chain(ChainCommand::Show) => {
+            let blocks: Vec<Block> =
+                from_result(rpc::call(rpc_addr, &token, "chain_blocks", json!({})).await?)?;
+            for (i, block) in blocks.iter().enumerate() {
+                println!("{}{}{}", "-".repeat(14), i, "-".repeat(15));
+                print!("{}", block);
+            }
+        }
+        Command::Chain(ChainCommand::Verify) => {
+            let verified = rpc::call(rpc_addr, &token, "chain_verify", json!({})).await?;
+            if verified["valid"] == json!(true) {
+                println!("Chain up to height {} is valid", verified["height"]);
+            } else {
+                println!("Chain is invalid: {}", verified["error"]);
+                std::process::exit(1);
+            }
+        }
+        Command::Peers(PeersCommand::List) => {
+            let peers: Vec<String> =
+                from_result(rpc::call(rpc_addr, &token, "peers_list", json!({})).await?)?;
+            for peer in peers {
+                println!("{}", peer);
+            }
+        }
+        Command::Mempool(MempoolCommand::List) => {
+            let txs: Vec<Transaction> =
+                from_result(rpc::call(rpc_addr, &token, "mempool_list", json!({})).await?)?;
+            for (i, tx) in txs.iter().enumerate() {
+                println!("{}. {}", i + 1, tx);
+            }
+        }
+        Command::Mine => {
+            let mined = rpc::call(rpc_addr, &token, "mine_block", json!({})).await?;
+            println!(
+                "Mined block {} at height {}",
+                mined["hash"], mined["height"]
+            );
+        }
+        Command::Node(NodeCommand::Run) => unreachable!("the node is not a client"),
+    }
+    Ok(())
+}
+
+/// Asked for a block to be mined, from the menu or over RPC.
+enum MineRequest {
+    Menu,
+    Rpc(rpc::Call),
+}
+
+/// Tells everyone `waiting` the hash and height of the block mined for them,
+/// or why none was.
+fn answer_mined(waiting: &mut Vec<MineRequest>, mined: Result<(String, usize), rpc::RpcError>) {
+    for request in waiting.drain(..) {
+        match request {
+            // The mined block itself is announced either way
+            MineRequest::Menu => {
+                if let Err(e) = &mined {
+                    println!("Can not mine block: {} \r\n", e.message);
+                }
+            }
+            MineRequest::Rpc(call) => rpc::answer_mined(call, mined.clone()),
+        }
+    }
+}
+
+/// Starts mining the pending transactions for this node, off the event loop.
+fn start_mining(swarm: &Swarm<p2p::AppBehaviour>) -> Result<MiningJob, Error> {
+    let behaviour = swarm.behaviour();
+    let template = behaviour.node.block_template(&behaviour.peer_id.to_string())?;
+    for tx in &template.skipped {
+        println!("Skipping tx {} \r\n", tx);
+    }
+    Ok(MiningJob::start(template))
+}
+
+fn from_result<T: serde::de::DeserializeOwned>(result: Value) -> Result<T, Error> {
+    serde_json::from_value(result)
+        .map_err(|e| rpc::RpcError::new(rpc::PARSE_ERROR, format!("bad result: {}", e)).into())
+}
+
+/// Runs a node taking RPC calls on `rpc_addr`, with the menu if
+/// `interactive`.
+async fn run_node(rpc_addr: SocketAddr, interactive: bool) -> Result<(), Error> {
     let selections = &[
         "Create block",
         "View local blockchain",
         "Generate transaction",
         "View nodes",
         "View pending txs",
+        "Check balance",
+        "View banned peers",
     ];
 
-    let blockchain = Blockchain::new(0, 3, 256);
-    let node = node::Node { 
-        blockchain: blockchain,
-        last_time_synced: 0.0,
+    let spec = match std::env::var("ECHAIN_CHAIN_SPEC") {
+        Ok(path) => ChainSpec::from_file(path)?,
+        Err(_) => ChainSpec::dev(),
     };
-    let mut pending_txs: Vec<Transaction> = vec![];
-
-    let (response_sender, mut response_rcv) = mpsc::unbounded_channel();
-    let (init_sender, mut init_rcv) = mpsc::unbounded_channel();
+    let blockchain = Blockchain::new(&spec, 0, 256);
+    let data_dir = data_dir();
+    let (store, stored) = BlockStore::open(&data_dir)?;
+    let node = Node::with_store(blockchain, store, stored)?;
 
     let (cli_sender, mut cli_rcv) = mpsc::unbounded_channel();
+    let (rpc_sender, mut rpc_rcv) = mpsc::unbounded_channel();
+
+    let id_keys = keystore::load_or_create(&data_dir)?;
+    let network = NetworkConfig::from_env()?;
+    let mut swarm = p2p::swarm_factory(&id_keys, node, &network).await?.build();
 
-    let mut swarm = swarm_factory(node, response_sender)
-        .await
-        .build();
+    Swarm::listen_on(&mut swarm, network.listen_addr.clone())
+        .map_err(|e| Error::Network(e.to_string()))?;
+    let mut redial = time::interval(p2p::REDIAL_INTERVAL);
+    let mut mine_tick = time::interval(p2p::MINING_INTERVAL);
+    mine_tick.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
 
-    Swarm::listen_on(
-        &mut swarm,
-        "/ip4/0.0.0.0/tcp/0"
-            .parse()
-            .expect("can get a local socket"),
-    )
-    .expect("swarm can be started");
+    if !rpc_addr.ip().is_loopback() {
+        println!("RPC is reachable from other hosts on {} \r\n", rpc_addr);
+    }
+    let token = rpc_token(&data_dir, true)?;
+    match TcpListener::bind(rpc_addr).await {
+        Ok(listener) => {
+            spawn(rpc::serve(listener, token, rpc_sender));
+        }
+        // The menu still works without RPC, a headless node does not
+        Err(e) if interactive => {
+            println!("No RPC, can not listen on {}: {} \r\n", rpc_addr, e)
+        }
+        Err(e) => return Err(e.into()),
+    }
 
- 
     // Wallet num is peer id
     let wallen_num = swarm.behaviour().peer_id;
-    thread::spawn(move || loop {
-        let selection = Select::with_theme(&ColorfulTheme::default())
-            .clear(true)
-            .with_prompt(format!("Your wallet num is {}\r\nPick option\r\n", wallen_num))
-            .default(0)
-            .items(&selections[..])
-            .interact()
-            .unwrap();
+    if interactive {
+        thread::spawn(move || loop {
+            let selection = Select::with_theme(&ColorfulTheme::default())
+                .clear(true)
+                .with_prompt(format!("Your wallet num is {}\r\nPick option\r\n", wallen_num))
+                .default(0)
+                .items(&selections[..])
+                .interact()
+                .unwrap();
 
-        cli_sender.send(selection).unwrap();
+            // Balance lookups need an address to look up
+            let address = if selection == 5 {
+                Some(
+                    Input::<String>::with_theme(&ColorfulTheme::default())
+                        .with_prompt("Address")
+                        .default(wallen_num.to_string())
+                        .interact_text()
+                        .unwrap(),
+                )
+            } else {
+                None
+            };
 
-        // block sync only on interaction
-        init_sender.send(true).expect("can send msg to init channel");
-
-    });
+            cli_sender.send((selection, address)).unwrap();
+        });
+    } else {
+        println!("Node {} taking RPC calls on {}", wallen_num, rpc_addr);
+    }
 
+    // Chains are synced with peers as they are discovered or dialed, and
+    // with the sender of any block whose parent is missing
+    let mut mining: Option<MiningJob> = None;
+    // Who asked for a block, answered once one is in
+    let mut waiting: Vec<MineRequest> = vec![];
     loop {
-        let mut selection = 99;
-        let evt = {
-            select! {
-                response = response_rcv.recv() => {
-                    Some(p2p::EventType::LocalChainResponse(response.expect("response exists")))
-                },
-                _init = init_rcv.recv() => {
-                    Some(p2p::EventType::Init)
-                },
-                _selection = cli_rcv.recv() => {
-                    selection = _selection.unwrap();
-                    Some(p2p::EventType::Cli)
-
-                },
-                _event = swarm.select_next_some() => {
-                    None
-                },
+        for e in swarm.behaviour_mut().node.take_store_errors() {
+            println!("Block store: {} \r\n", e);
+        }
+
+        // A search on a tip that moved on can only find a stale block
+        if let Some(job) = &mining {
+            let behaviour = swarm.behaviour();
+            let wanted = behaviour.mining || !waiting.is_empty();
+            if !wanted || job.prev_hash != behaviour.node.blockchain.tip_hash() {
+                job.cancel();
             }
-        };
+        }
 
-        if let Some(event) = evt {
-            match event {
-                p2p::EventType::Init => {
-                    let topic = swarm.behaviour_mut().blockchain_topic.clone();
-                    let peers = p2p::get_list_peers(&swarm);
-                    if !peers.is_empty() {
-                        let req = p2p::LocalChainRequest {
-                            from_peer_id: peers
-                                .iter()
-                                .last()
-                                .expect("at least one peer")
-                                .to_string(),
-                        };
-
-                        let json = serde_json::to_string(&req).expect("can jsonify request");
-                        swarm
-                            .behaviour_mut()
-                            .floodsub
-                            .publish(topic, json.as_bytes());
+        // A block asked for is mined right away, not on the next tick
+        if mining.is_none() && !waiting.is_empty() {
+            match start_mining(&swarm) {
+                Ok(job) => mining = Some(job),
+                Err(e) => answer_mined(&mut waiting, Err(e.into())),
+            }
+        }
+
+        let (selection, address) = select! {
+            picked = cli_rcv.recv(), if interactive => picked.expect("cli thread is running"),
+            Some(call) = rpc_rcv.recv() => {
+                if let Some(call) = rpc::dispatch(&mut swarm, &id_keys, call) {
+                    waiting.push(MineRequest::Rpc(call));
+                }
+                continue;
+            }
+            event = swarm.select_next_some() => {
+                if let SwarmEvent::NewListenAddr { address, .. } = event {
+                    if !interactive {
+                        println!("Listening on {}/p2p/{}", address, wallen_num);
                     }
                 }
-                p2p::EventType::LocalChainResponse(resp) => {
-                    let topic = swarm.behaviour_mut().blockchain_topic.clone();
-                    let json = serde_json::to_string(&resp).expect("can jsonify response");
-                    swarm
-                        .behaviour_mut()
-                        .floodsub
-                        .publish(topic, json.as_bytes());
+                p2p::update_bans(&mut swarm);
+                continue;
+            }
+            _ = redial.tick() => {
+                p2p::update_bans(&mut swarm);
+                p2p::dial_bootstrap(&mut swarm);
+                continue;
+            }
+            _ = mine_tick.tick(), if swarm.behaviour().mining && mining.is_none() => {
+                match start_mining(&swarm) {
+                    Ok(job) => mining = Some(job),
+                    // Nothing to mine yet, try again next tick
+                    Err(Error::NotEnoughTransactions { .. }) => {}
+                    Err(e) => println!("Can not mine block: {} \r\n", e),
                 }
-                p2p::EventType::Cli => {
-                    // let selection = cli_rcv.recv().await.unwrap();
-                    if selection == 0 {
-                        clearscreen::clear().expect("failed to clear screen");
-                        thread::sleep(Duration::from_millis(100));
-
-                        let suc = swarm
-                            .behaviour_mut()
-                            .node
-                            .blockchain
-                            .try_mine(pending_txs.clone());
-                        if suc {
-                            // IF successfull mining, then we broadcast the block to the network
-                            // https://www.oreilly.com/library/view/mastering-bitcoin/9781491902639/ch08.html
-                            // However, there is no complex logic like orphans blocks or mempool here yet.
-                            pending_txs.clear();
-                            let topic = swarm.behaviour_mut().blockchain_topic.clone();
-                            let json = serde_json::to_string(
-                                &swarm.behaviour_mut().node.blockchain.chain.last(),
-                            )
-                            .expect("can jsonify request");
-
-                            swarm
-                                .behaviour_mut()
-                                .floodsub
-                                .publish(topic, json.as_bytes());
-                        }
-                    }
-                    if selection == 1 {
-                        clearscreen::clear().expect("failed to clear screen");
-                        thread::sleep(Duration::from_millis(100));
-                        print!("Last time from syncing chains: {}. \r\n\r\n", &swarm.behaviour_mut().node.last_time_synced);
-                        handle_print_chain(&swarm.behaviour_mut().node.blockchain);
-                        print!("\n");
-                    }
-                    if selection == 2 {
-                        clearscreen::clear().expect("failed to clear screen");
-
-                        // We will send 100 coins to random peer
-                        let peers = p2p::get_list_peers(&swarm);
-                        let to = peers.choose(&mut rand::thread_rng());
-
-                        let transaction = Transaction {
-                            from: wallen_num.to_string().clone(),
-                            to: to.unwrap().to_string(),
-                            amount: 100,
-                            time: SystemTime::now(),
-                        };
-                        thread::sleep(Duration::from_millis(100));
-                        println!("Generated tx \n {}", transaction);
-
-                        pending_txs.push(transaction);
-                    }
-                    if selection == 3 {
-                        clearscreen::clear().expect("failed to clear screen");
-                        let peers = p2p::get_list_peers(&swarm);
-                        thread::sleep(Duration::from_millis(100));
-                        print!("Peers len {}. Peers list: \r\n", peers.len());
-                        for mut peer in peers {
-                            peer = peer.split_whitespace().collect();
-                            print!("{}\r\n", peer);
+                continue;
+            }
+            mined = async { mining.as_mut().expect("job is running").finish().await },
+                if mining.is_some() => {
+                mining = None;
+                // Cancelled or stale, the waiting calls get the next block
+                if let Some(block) = mined {
+                    let hash = block.hash.clone();
+                    match swarm.behaviour_mut().add_mined(block) {
+                        Ok(BlockOutcome::Connected(_)) | Ok(BlockOutcome::Reorganized(_)) => {
+                            println!("Mined block {} \r\n", hash);
+                            let height = swarm.behaviour().node.blockchain.height_of(&hash);
+                            answer_mined(&mut waiting, Ok((hash, height.unwrap_or_default())));
                         }
-                        print!("\n");
-                    }
-                    if selection == 4 {
-                        clearscreen::clear().expect("failed to clear screen");
-                        thread::sleep(Duration::from_millis(100));
-                        print!("Total txs {}. Tx list: \r\n", pending_txs.len());
-                        for i in 0..pending_txs.len() {
-                            print!("{}. {} \r\n", i + 1, &pending_txs[i]);
+                        Ok(_) => {}
+                        Err(e) => {
+                            println!("Can not add mined block: {} \r\n", e);
+                            answer_mined(&mut waiting, Err(e.into()));
                         }
-                        print!("\n");
                     }
                 }
+                continue;
+            }
+        };
+
+        if selection == 0 {
+            clearscreen::clear().expect("failed to clear screen");
+            thread::sleep(Duration::from_millis(100));
+
+            // Mined beside the event loop, and broadcast once found
+            println!("Mining block \r\n");
+            waiting.push(MineRequest::Menu);
+        }
+        if selection == 1 {
+            clearscreen::clear().expect("failed to clear screen");
+            thread::sleep(Duration::from_millis(100));
+            print!("Last time from syncing chains: {}. \r\n\r\n", &swarm.behaviour_mut().node.last_time_synced);
+            handle_print_chain(&swarm.behaviour_mut().node.blockchain);
+            println!();
+        }
+        if selection == 2 {
+            clearscreen::clear().expect("failed to clear screen");
+
+            // We will send 100 coins to random peer
+            let peers = p2p::get_list_peers(&swarm);
+            let to = match peers.choose(&mut rand::thread_rng()) {
+                Some(to) => to.to_string(),
+                None => {
+                    println!("No peers to send coins to \r\n");
+                    continue;
+                }
+            };
+            thread::sleep(Duration::from_millis(100));
+
+            match swarm.behaviour_mut().send_coins(&id_keys, to, 100, None) {
+                Ok(transaction) => println!("Generated tx \n {}", transaction),
+                Err(e) => println!("Rejected tx: {}", e),
+            }
+        }
+        if selection == 3 {
+            clearscreen::clear().expect("failed to clear screen");
+            let peers = p2p::get_list_peers(&swarm);
+            thread::sleep(Duration::from_millis(100));
+            print!("Peers len {}. Peers list: \r\n", peers.len());
+            for mut peer in peers {
+                peer = peer.split_whitespace().collect();
+                print!("{}\r\n", peer);
+            }
+            println!();
+        }
+        if selection == 4 {
+            clearscreen::clear().expect("failed to clear screen");
+            thread::sleep(Duration::from_millis(100));
+            let pending_txs = swarm.behaviour().node.mempool.transactions();
+            print!("Total txs {}. Tx list: \r\n", pending_txs.len());
+            for (i, tx) in pending_txs.iter().enumerate() {
+                print!("{}. {} \r\n", i + 1, tx);
+            }
+            println!();
+        }
+        if selection == 5 {
+            clearscreen::clear().expect("failed to clear screen");
+            thread::sleep(Duration::from_millis(100));
+            if let Some(address) = address {
+                let balance = swarm.behaviour().node.balance(&address);
+                print!("Balance of {}: {} coins \r\n", address, balance);
+            }
+            println!();
+        }
+        if selection == 6 {
+            clearscreen::clear().expect("failed to clear screen");
+            thread::sleep(Duration::from_millis(100));
+            let banned = swarm.behaviour().scores.banned(Instant::now());
+            print!("Banned peers {}. Peers list: \r\n", banned.len());
+            for (peer, left) in banned {
+                print!("{} for another {}s \r\n", peer, left.as_secs());
             }
+            println!();
         }
     }
 }
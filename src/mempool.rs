@@ -0,0 +1,197 @@
+use derive_more::Display;
+use std::collections::{HashMap, VecDeque};
+
+use crate::{
+    block::Block,
+    state::{AccountState, PendingState, StateError},
+    transaction::{Transaction, TransactionError},
+};
+
+pub const DEFAULT_MAX_MEMPOOL_TXS: usize = 4096;
+
+/// Reason a transaction was not admitted to the mempool.
+#[derive(Clone, Display, PartialEq, Debug)]
+pub enum MempoolError {
+    #[display(fmt = "transaction {} is already pending", _0)]
+    Duplicate(String),
+    #[display(fmt = "coinbase transactions are only valid inside a block")]
+    Coinbase,
+    #[display(fmt = "{}", _0)]
+    InvalidTransaction(TransactionError),
+    #[display(fmt = "{}", _0)]
+    StateViolation(StateError),
+    #[display(fmt = "mempool is full")]
+    Full,
+}
+
+impl std::error::Error for MempoolError {}
+
+/// Signed transactions waiting to be mined, keyed by id. Each one applies on
+/// top of the chain state plus every pending transaction admitted before it.
+pub struct Mempool {
+    txs: HashMap<String, Transaction>,
+    // Ids in admission order, oldest first
+    order: VecDeque<String>,
+    capacity: usize,
+}
+
+impl Mempool {
+    pub fn new(capacity: usize) -> Self {
+        Mempool {
+            txs: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.txs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.txs.is_empty()
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.txs.contains_key(id)
+    }
+
+    /// Pending transactions in admission order.
+    pub fn transactions(&self) -> Vec<Transaction> {
+        self.order.iter().map(|id| self.txs[id].clone()).collect()
+    }
+
+    /// Admits `tx` if it is signed by its sender and still applies once the
+    /// pending transactions are spent from `state`. Returns the id.
+    pub fn insert(&mut self, tx: Transaction, state: &AccountState) -> Result<String, MempoolError> {
+        let id = tx.id();
+        if self.contains(&id) {
+            return Err(MempoolError::Duplicate(id));
+        }
+
+        if tx.is_coinbase() {
+            return Err(MempoolError::Coinbase);
+        }
+
+        if self.len() >= self.capacity {
+            return Err(MempoolError::Full);
+        }
+
+        tx.verify().map_err(MempoolError::InvalidTransaction)?;
+        self.pending_state(state)
+            .check_transaction(&tx)
+            .map_err(MempoolError::StateViolation)?;
+
+        self.order.push_back(id.clone());
+        self.txs.insert(id.clone(), tx);
+        Ok(id)
+    }
+
+    /// Drops the transactions `block` included.
+    pub fn remove_included(&mut self, block: &Block) {
+        for tx in &block.transactions {
+            self.txs.remove(&tx.id());
+        }
+        let txs = &self.txs;
+        self.order.retain(|id| txs.contains_key(id));
+    }
+
+    /// Re-checks every pending transaction against a new chain state, in
+    /// admission order, and drops the ones that no longer apply.
+    pub fn revalidate(&mut self, state: &AccountState) {
+        let mut state = PendingState::new(state);
+        let txs = &mut self.txs;
+        self.order.retain(|id| {
+            if state.apply_transaction(&txs[id]).is_ok() {
+                true
+            } else {
+                txs.remove(id);
+                false
+            }
+        });
+    }
+
+    fn pending_state<'a>(&self, state: &'a AccountState) -> PendingState<'a> {
+        let mut state = PendingState::new(state);
+        for id in &self.order {
+            // Pending transactions were checked on admission and revalidation
+            let _ = state.apply_transaction(&self.txs[id]);
+        }
+        state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::tests::mine;
+    use crate::blockchain::tests::funded_spec;
+    use crate::mempool::{Mempool, MempoolError};
+    use crate::state::{AccountState, StateError};
+    use crate::transaction::{Transaction, TransactionError};
+    use libp2p::identity::Keypair;
+    use libp2p::PeerId;
+
+    #[test]
+    fn test_mempool() {
+        let alice = Keypair::generate_ed25519();
+        let alice_addr = PeerId::from(alice.public()).to_string();
+        let genesis = funded_spec(&alice, 100).genesis_block();
+        let state = AccountState::genesis(&genesis);
+
+        let mut pool = Mempool::new(2);
+        let first = Transaction::new_signed(&alice, String::from("Bob"), 60, 1).unwrap();
+        let id = pool.insert(first.clone(), &state).unwrap();
+        assert_eq!(id, first.id());
+        assert_eq!(
+            pool.insert(first.clone(), &state),
+            Err(MempoolError::Duplicate(first.id()))
+        );
+
+        // Pending spends count against the sender's balance
+        let second = Transaction::new_signed(&alice, String::from("Carol"), 60, 1).unwrap();
+        assert_eq!(
+            pool.insert(second, &state),
+            Err(MempoolError::StateViolation(
+                StateError::InsufficientFunds {
+                    address: alice_addr,
+                    balance: 39,
+                    amount: 61
+                }
+            ))
+        );
+
+        let mut forged = Transaction::new_signed(&alice, String::from("Carol"), 10, 1).unwrap();
+        forged.amount = 20;
+        assert_eq!(
+            pool.insert(forged, &state),
+            Err(MempoolError::InvalidTransaction(
+                TransactionError::InvalidSignature
+            ))
+        );
+        assert_eq!(
+            pool.insert(Transaction::coinbase(String::from("miner"), 50), &state),
+            Err(MempoolError::Coinbase)
+        );
+
+        let third = Transaction::new_signed(&alice, String::from("Carol"), 10, 1).unwrap();
+        pool.insert(third.clone(), &state).unwrap();
+        let fourth = Transaction::new_signed(&alice, String::from("Dave"), 1, 1).unwrap();
+        assert_eq!(pool.insert(fourth, &state), Err(MempoolError::Full));
+        assert_eq!(pool.transactions(), vec![first.clone(), third.clone()]);
+
+        let block = mine(&genesis.hash, vec![first.clone()], 4);
+        let mut next = state.clone();
+        next.apply_block(&block, 0).unwrap();
+        pool.remove_included(&block);
+        pool.revalidate(&next);
+        assert!(!pool.contains(&first.id()));
+        assert_eq!(pool.transactions(), vec![third.clone()]);
+
+        // A state where Alice spent everything drops what she can not pay for
+        let mut drained = next.clone();
+        let rest = Transaction::new_signed(&alice, String::from("Bob"), 38, 1).unwrap();
+        drained.apply_transaction(&rest).unwrap();
+        pool.revalidate(&drained);
+        assert!(pool.is_empty());
+    }
+}
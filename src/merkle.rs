@@ -0,0 +1,190 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Root of a tree without leaves.
+pub const EMPTY_ROOT: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+/// A sibling hash on the way from a leaf to the root, and which side of the
+/// running hash it goes on.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ProofStep {
+    pub hash: String,
+    pub side: Side,
+}
+
+/// Shows that a leaf is part of a tree while only knowing the tree's root.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct MerkleProof {
+    pub index: usize,
+    pub steps: Vec<ProofStep>,
+}
+
+// Leaves and interior nodes are hashed behind different prefixes, so a leaf
+// can never pass for a pair of other leaves
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+fn hash_leaf(leaf: &str) -> String {
+    let hashed = Sha256::new()
+        .chain_update([LEAF_PREFIX])
+        .chain_update(leaf)
+        .finalize();
+    format!("{:x}", hashed)
+}
+
+fn hash_pair(left: &str, right: &str) -> String {
+    let hashed = Sha256::new()
+        .chain_update([NODE_PREFIX])
+        .chain_update(left)
+        .chain_update(right)
+        .finalize();
+    format!("{:x}", hashed)
+}
+
+/// Hashes one level of the tree into the next. An odd node out is paired
+/// with `EMPTY_ROOT` rather than with itself, so repeating the last leaf
+/// changes the root, and every node but the root has a sibling.
+fn next_level(level: &[String]) -> Vec<String> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => hash_pair(left, right),
+            [single] => hash_pair(single, EMPTY_ROOT),
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+pub fn merkle_root(leaves: &[String]) -> String {
+    if leaves.is_empty() {
+        return String::from(EMPTY_ROOT);
+    }
+
+    let mut level: Vec<String> = leaves.iter().map(|leaf| hash_leaf(leaf)).collect();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level.remove(0)
+}
+
+/// Proof that `leaves[index]` is part of the tree, or `None` if out of range.
+pub fn merkle_proof(leaves: &[String], index: usize) -> Option<MerkleProof> {
+    if index >= leaves.len() {
+        return None;
+    }
+
+    let mut steps = vec![];
+    let mut level: Vec<String> = leaves.iter().map(|leaf| hash_leaf(leaf)).collect();
+    let mut position = index;
+    while level.len() > 1 {
+        let sibling = position ^ 1;
+        steps.push(ProofStep {
+            hash: level
+                .get(sibling)
+                .cloned()
+                .unwrap_or_else(|| String::from(EMPTY_ROOT)),
+            side: if sibling < position {
+                Side::Left
+            } else {
+                Side::Right
+            },
+        });
+        level = next_level(&level);
+        position /= 2;
+    }
+
+    Some(MerkleProof { index, steps })
+}
+
+impl MerkleProof {
+    /// Checks that `leaf` sits at `index` of the tree with `root`. There is a
+    /// step for every level, so the sides of the steps spell out `index` in
+    /// binary, lowest bit first, with `Left` for a one.
+    pub fn verify(&self, leaf: &str, root: &str) -> bool {
+        let mut hash = hash_leaf(leaf);
+        let mut position = self.index;
+        for step in &self.steps {
+            hash = match (step.side, position % 2) {
+                (Side::Left, 1) => hash_pair(&step.hash, &hash),
+                (Side::Right, 0) => hash_pair(&hash, &step.hash),
+                _ => return false,
+            };
+            position /= 2;
+        }
+        position == 0 && hash == root
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::merkle::{hash_leaf, hash_pair, merkle_proof, merkle_root, MerkleProof, EMPTY_ROOT};
+
+    fn leaves(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("tx{}", i)).collect()
+    }
+
+    #[test]
+    fn test_merkle_root() {
+        assert_eq!(merkle_root(&[]), EMPTY_ROOT);
+        assert_eq!(merkle_root(&leaves(1)), hash_leaf("tx0"));
+
+        let mut roots: Vec<String> = (1..8).map(|count| merkle_root(&leaves(count))).collect();
+        roots.dedup();
+        assert_eq!(roots.len(), 7);
+
+        let mut swapped = leaves(4);
+        swapped.swap(1, 2);
+        assert_ne!(merkle_root(&leaves(4)), merkle_root(&swapped));
+
+        // An odd leaf is not paired with itself, so repeating it changes the root
+        let mut repeated = leaves(3);
+        repeated.push(String::from("tx2"));
+        assert_ne!(merkle_root(&leaves(3)), merkle_root(&repeated));
+
+        // An interior node passed off as a leaf does not give the same root
+        let leaves = leaves(3);
+        let pair = hash_pair(&hash_leaf(&leaves[0]), &hash_leaf(&leaves[1]));
+        assert_ne!(
+            merkle_root(&leaves),
+            merkle_root(&[pair, leaves[2].clone()])
+        );
+    }
+
+    #[test]
+    fn test_merkle_proof() {
+        for count in 1..10 {
+            let leaves = leaves(count);
+            let root = merkle_root(&leaves);
+            for (index, leaf) in leaves.iter().enumerate() {
+                let proof = merkle_proof(&leaves, index).unwrap();
+                assert!(proof.verify(leaf, &root));
+                assert!(!proof.verify("tx-unknown", &root));
+            }
+            assert!(merkle_proof(&leaves, count).is_none());
+        }
+
+        let leaves = leaves(5);
+        let root = merkle_root(&leaves);
+        let mut proof = merkle_proof(&leaves, 1).unwrap();
+        proof.steps[0].hash = String::from("tx7");
+        assert!(!proof.verify(&leaves[1], &root));
+
+        // The steps have to lead to the claimed index
+        for index in 0..5 {
+            let proof = merkle_proof(&leaves, index).unwrap();
+            for other in (0..8).filter(|other| *other != index) {
+                let moved = MerkleProof {
+                    index: other,
+                    ..proof.clone()
+                };
+                assert!(!moved.verify(&leaves[index], &root));
+            }
+        }
+    }
+}
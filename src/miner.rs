@@ -0,0 +1,58 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::task::{self, JoinHandle};
+
+use crate::{block::Block, blockchain::BlockTemplate};
+
+/// A block search running on a blocking thread, so the node keeps handling
+/// gossip, sync and RPC calls while it mines.
+pub struct MiningJob {
+    /// Tip the block builds on. The search is stale once the tip moves.
+    pub prev_hash: String,
+    cancel: Arc<AtomicBool>,
+    handle: JoinHandle<Option<Block>>,
+}
+
+impl MiningJob {
+    pub fn start(template: BlockTemplate) -> Self {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let flag = cancel.clone();
+        MiningJob {
+            prev_hash: template.prev_hash.clone(),
+            cancel,
+            handle: task::spawn_blocking(move || template.search(&flag)),
+        }
+    }
+
+    /// Asks the search to stop. `finish` then returns soon after.
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+
+    /// The mined block, or `None` if the search was cancelled.
+    pub async fn finish(&mut self) -> Option<Block> {
+        (&mut self.handle).await.ok().flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blockchain::tests::test_spec;
+    use crate::blockchain::Blockchain;
+    use crate::miner::MiningJob;
+
+    #[tokio::test]
+    async fn test_mining_job() {
+        let mut chain = Blockchain::new(&test_spec(), 0, 256);
+        let template = chain.template("Miner", vec![]).unwrap();
+        let block = MiningJob::start(template).finish().await.unwrap();
+        chain.add_block(block).unwrap();
+
+        // Out of reach, so only cancelling ends it
+        let mut template = chain.template("Miner", vec![]).unwrap();
+        template.difficulty = 255;
+        let mut job = MiningJob::start(template);
+        job.cancel();
+        assert!(job.finish().await.is_none());
+    }
+}
@@ -1,45 +1,351 @@
 use std::time::SystemTime;
 
-use crate::blockchain::Blockchain;
+use crate::{
+    block::{Block, BlockError},
+    blockchain::{BlockTemplate, Blockchain},
+    error::Error,
+    mempool::{Mempool, MempoolError, DEFAULT_MAX_MEMPOOL_TXS},
+    orphans::{OrphanPool, DEFAULT_MAX_ORPHANS},
+    protocol::ChainStatus,
+    storage::{BlockStore, StorageError},
+    transaction::Transaction,
+};
 
 pub struct Node {
     pub blockchain: Blockchain,
+    pub orphans: OrphanPool,
+    pub mempool: Mempool,
     pub last_time_synced: f64,
+    // Where accepted blocks are written, if the chain is kept on disk
+    store: Option<BlockStore>,
+    // Store problems the node recovered from, see `take_store_errors`
+    store_errors: Vec<StorageError>,
+}
+
+/// What became of a block passed to `Node::receive_block`.
+#[derive(Debug, PartialEq)]
+pub enum BlockOutcome {
+    /// Hashes appended to the chain: the received block, then any orphans it
+    /// unblocked.
+    Connected(Vec<String>),
+    /// Parent is unknown, so the block waits in the orphan pool for `missing`.
+    Orphaned { missing: String },
+    /// Already in the chain or the orphan pool.
+    Duplicate,
+    /// Builds on a block below the tip. It is valid there, but the branch it
+    /// ends carries no more work than the chain, which stays as it is.
+    SideBranch,
+    /// Ends a branch off a block below the tip that outweighs the chain, so
+    /// the node switched to it.
+    Reorganized(ReorgReport),
+}
+
+/// Blocks that left and joined the local chain when resolving a conflict.
+/// Both lists are empty when the local chain was kept.
+#[derive(Debug, Default, PartialEq)]
+pub struct ReorgReport {
+    /// Local blocks past the fork point, in chain order
+    pub removed: Vec<Block>,
+    /// Blocks of the adopted chain past the fork point, in chain order
+    pub added: Vec<Block>,
+}
+
+impl ReorgReport {
+    pub fn is_empty(&self) -> bool {
+        self.removed.is_empty() && self.added.is_empty()
+    }
 }
 
 impl Node {
-    pub fn resolve_chain_conflict(&mut self, other: &Blockchain) {
-        let own_valid = self.blockchain.is_valid();
-        let other_valid = other.is_valid();
-        let correct_chain;
-        if own_valid && other_valid {
-            if self.blockchain.len() >= other.len() {
-                correct_chain = &self.blockchain;
-            } else {
-                correct_chain = &other;
-            }
-        } else if other_valid {
-            correct_chain = &other;
-        } else if own_valid {
-            correct_chain = &self.blockchain;
-        } else {
-            panic!("All chains are invalid");
+    pub fn new(blockchain: Blockchain) -> Self {
+        Node {
+            blockchain,
+            orphans: OrphanPool::new(DEFAULT_MAX_ORPHANS),
+            mempool: Mempool::new(DEFAULT_MAX_MEMPOOL_TXS),
+            last_time_synced: 0.0,
+            store: None,
+            store_errors: vec![],
+        }
+    }
+
+    /// Node that continues the chain of `stored` blocks, as read back from
+    /// `store`, and writes every block it accepts from now on. Each stored
+    /// block is validated again; the first invalid one is dropped from the
+    /// store along with everything after it. Data dropped either way is
+    /// reported by `take_store_errors`.
+    pub fn with_store(
+        blockchain: Blockchain,
+        mut store: BlockStore,
+        stored: Vec<Block>,
+    ) -> Result<Self, StorageError> {
+        let mut blockchain = blockchain;
+        let mut store_errors = vec![];
+        if store.dropped_bytes() > 0 {
+            store_errors.push(StorageError::Unreadable {
+                height: stored.len(),
+                bytes: store.dropped_bytes(),
+            });
+        }
+        match stored.first() {
+            None => store.append(&blockchain.chain[0])?,
+            Some(genesis) if genesis.hash != blockchain.genesis_hash() => {
+                return Err(StorageError::GenesisMismatch {
+                    expected: blockchain.genesis_hash().to_string(),
+                    found: genesis.hash.clone(),
+                })
+            }
+            Some(_) => {}
+        }
+
+        for (height, block) in stored.into_iter().enumerate().skip(1) {
+            if let Err(reason) = blockchain.add_block(block) {
+                store.truncate(height)?;
+                store_errors.push(StorageError::InvalidBlock { height, reason });
+                break;
+            }
+        }
+
+        let mut node = Node::new(blockchain);
+        node.store = Some(store);
+        node.store_errors = store_errors;
+        Ok(node)
+    }
+
+    /// Problems with the store since the last call: data dropped when it was
+    /// opened and writes that failed. A failed write is retried with the
+    /// next block the node accepts.
+    pub fn take_store_errors(&mut self) -> Vec<StorageError> {
+        std::mem::take(&mut self.store_errors)
+    }
+
+    /// Makes the store match the chain from `height` on. A store left behind
+    /// by an earlier failed write is caught up from where it stopped.
+    fn persist(&mut self, height: usize) {
+        let store = match self.store.as_mut() {
+            Some(store) => store,
+            None => return,
+        };
+
+        let height = height.min(store.len());
+        let blocks = &self.blockchain.chain[height..];
+        let written = store
+            .truncate(height)
+            .and_then(|_| blocks.iter().try_for_each(|block| store.append(block)));
+        if let Err(e) = written {
+            self.store_errors.push(e);
+        }
+    }
+
+    pub fn balance(&self, address: &str) -> u64 {
+        self.blockchain.balance(address)
+    }
+
+    pub fn status(&self) -> ChainStatus {
+        ChainStatus {
+            genesis_hash: self.blockchain.genesis_hash().to_string(),
+            height: self.blockchain.len().saturating_sub(1),
+            tip_hash: self.blockchain.tip_hash().to_string(),
+            total_work: self.blockchain.total_work(),
         }
-        self.last_time_synced = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64();
-		
+    }
 
-		self.blockchain.chain = correct_chain.chain.clone();
+    /// Admits a transaction to the mempool if it applies on top of the tip
+    /// and the transactions already pending.
+    pub fn submit_transaction(&mut self, tx: Transaction) -> Result<String, MempoolError> {
+        self.mempool.insert(tx, self.blockchain.state())
+    }
+
+    /// The next block to mine from the pending transactions, paying the
+    /// reward to `miner`. Pass the mined block to `receive_block`.
+    pub fn block_template(&self, miner: &str) -> Result<BlockTemplate, Error> {
+        self.blockchain.template(miner, self.mempool.transactions())
+    }
+
+    /// Mines the pending transactions, paying the reward to `miner`.
+    pub fn mine(&mut self, miner: &str) -> Result<(), Error> {
+        let height = self.blockchain.len();
+        self.blockchain.try_mine(miner, self.mempool.transactions())?;
+        self.persist(height);
+        let tip = self.blockchain.chain.last().cloned();
+        self.update_mempool(tip.as_slice(), &[]);
+        Ok(())
+    }
+
+    /// Evicts transactions of blocks that joined the chain and puts back the
+    /// ones of blocks that left it, then drops whatever no longer applies.
+    fn update_mempool(&mut self, added: &[Block], removed: &[Block]) {
+        let state = self.blockchain.state();
+        for tx in removed.iter().flat_map(|block| block.transactions.iter().skip(1)) {
+            let _ = self.mempool.insert(tx.clone(), state);
+        }
+        for block in added {
+            self.mempool.remove_included(block);
+        }
+        self.mempool.revalidate(state);
+    }
+
+    /// Appends a block received from the network. Blocks whose parent we have
+    /// not seen are pooled, after a parent-independent proof-of-work check,
+    /// and connected once the parent turns up. Blocks on a parent below the
+    /// tip are checked there and switched to if their branch wins.
+    pub fn receive_block(&mut self, block: Block) -> Result<BlockOutcome, BlockError> {
+        if self.blockchain.contains(&block.hash) || self.orphans.contains(&block.hash) {
+            return Ok(BlockOutcome::Duplicate);
+        }
+
+        match self.blockchain.height_of(&block.header.prev_hash) {
+            None => {
+                block.verify_hash()?;
+                let missing = block.header.prev_hash.clone();
+                self.orphans.insert(block);
+                return Ok(BlockOutcome::Orphaned { missing });
+            }
+            Some(parent) if parent + 1 < self.blockchain.len() => {
+                // Replaying a branch costs its whole prefix, so turn away
+                // blocks without the work their place on it demands first
+                block.verify_hash()?;
+                let expected = self.blockchain.difficulty_at(parent + 1);
+                if block.header.difficulty != expected {
+                    return Err(BlockError::DifficultyMismatch {
+                        expected,
+                        found: block.header.difficulty,
+                    });
+                }
+
+                let mut branch = self
+                    .blockchain
+                    .with_blocks(self.blockchain.chain[..=parent].to_vec());
+                branch.add_block(block)?;
+                if !self.outweighs(&branch) {
+                    return Ok(BlockOutcome::SideBranch);
+                }
+                return Ok(BlockOutcome::Reorganized(self.adopt(branch)));
+            }
+            Some(_) => {}
+        }
+
+        let height = self.blockchain.len();
+        self.blockchain.add_block(block.clone())?;
+
+        let mut connected = vec![block];
+        connected.extend(self.connect_orphans());
+        self.persist(height);
+        self.update_mempool(&connected, &[]);
+        Ok(BlockOutcome::Connected(
+            connected.into_iter().map(|block| block.hash).collect(),
+        ))
+    }
+
+    /// Appends pooled blocks that extend the tip until none do. Siblings that
+    /// lose out to the first valid child are dropped.
+    fn connect_orphans(&mut self) -> Vec<Block> {
+        let mut connected = vec![];
+        loop {
+            let tip = self.blockchain.tip_hash().to_string();
+            let mut extended = false;
+            for child in self.orphans.take_children(&tip) {
+                if !extended && self.blockchain.add_block(child.clone()).is_ok() {
+                    connected.push(child);
+                    extended = true;
+                }
+            }
+
+            if !extended {
+                return connected;
+            }
+        }
+    }
+
+    /// Keeps whichever valid chain carries the most accumulated proof-of-work.
+    /// The other chain is checked against our own consensus parameters, not
+    /// the ones it was sent with. Equal work is broken by the lower tip hash
+    /// so every node settles on the same chain. Fails, leaving the local
+    /// chain as it is, if neither chain is valid.
+    pub fn resolve_chain_conflict(&mut self, other: &Blockchain) -> Result<ReorgReport, Error> {
+        let other = self.blockchain.with_blocks(other.chain.clone());
+        let adopt_other = match (self.blockchain.validate(), other.validate()) {
+            (Ok(()), Ok(())) => self.outweighs(&other),
+            (Err(_), Ok(())) => true,
+            (Ok(()), Err(_)) => false,
+            (Err(local), Err(other)) => return Err(Error::InvalidChains { local, other }),
+        };
+        self.mark_synced();
+
+        if !adopt_other {
+            return Ok(ReorgReport::default());
+        }
+        Ok(self.adopt(other))
+    }
+
+    /// Records that the chain was just checked against or extended from a
+    /// peer's, as shown by `last_time_synced`.
+    pub fn mark_synced(&mut self) {
+        self.last_time_synced = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64();
+    }
+
+    /// Whether `other` carries more work than the chain, or as much with a
+    /// lower tip hash.
+    fn outweighs(&self, other: &Blockchain) -> bool {
+        let own_work = self.blockchain.total_work();
+        let other_work = other.total_work();
+        other_work > own_work
+            || (other_work == own_work && other.tip_hash() < self.blockchain.tip_hash())
+    }
+
+    /// Replaces the chain with the valid `other`, rewriting the store and the
+    /// mempool from the fork point.
+    fn adopt(&mut self, other: Blockchain) -> ReorgReport {
+        let fork_point = self
+            .blockchain
+            .chain
+            .iter()
+            .zip(other.chain.iter())
+            .take_while(|(own, theirs)| own.hash == theirs.hash)
+            .count();
+
+        let removed = self.blockchain.chain[fork_point..].to_vec();
+        let mut added = other.chain[fork_point..].to_vec();
+        self.blockchain = other;
+        added.extend(self.connect_orphans());
+        self.persist(fork_point);
+        self.update_mempool(&added, &removed);
+
+        ReorgReport { removed, added }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::block::tests::generate_blocks;
-    use crate::blockchain::tests::generate_blockchain;
-	use crate::node::Node;
+    use crate::block::tests::{generate_blocks, mine, mine_at};
+    use crate::block::BlockError;
+    use crate::blockchain::tests::{funded_spec, generate_blockchain, test_spec};
+    use crate::blockchain::Blockchain;
+    use crate::chain_spec::ChainSpec;
+    use crate::error::Error;
+    use crate::node::{BlockOutcome, Node};
+    use crate::storage::tests::temp_dir;
+    use crate::storage::{BlockStore, StorageError};
+    use crate::transaction::Transaction;
+    use libp2p::identity::Keypair;
+    use std::time::Duration;
+
+    fn spaced_chain(base: &Blockchain, len: u64, spacing: u64) -> Blockchain {
+        let mut chain = base.clone();
+        let start = chain.chain[0].header.time();
+        for i in 1..len {
+            let prev = chain.chain.last().unwrap().hash.clone();
+            let time = start + Duration::from_secs(spacing * i);
+            let block = mine_at(&prev, vec![], chain.next_difficulty(), time);
+            chain.add_block(block).unwrap();
+        }
+        chain
+    }
 
     #[test]
-    fn test_conflict(){
+    fn test_conflict() {
         let chain = generate_blockchain();
 
         assert!(chain.is_valid());
@@ -50,14 +356,253 @@ mod tests {
 
         assert!(!invalid_chain.is_valid());
 
-        let mut node = Node{
-            blockchain: invalid_chain,
-            last_time_synced: 0.0,
+        let mut node = Node::new(invalid_chain.clone());
+
+        // Neither chain is worth keeping, the node carries on with its own
+        assert!(matches!(
+            node.resolve_chain_conflict(&invalid_chain),
+            Err(Error::InvalidChains { .. })
+        ));
+        assert!(node.blockchain == invalid_chain);
+
+        let report = node.resolve_chain_conflict(&chain).unwrap();
+
+        assert!(node.blockchain == chain);
+        assert_eq!(report.removed.len(), 5);
+        assert_eq!(report.added, chain.chain[1..].to_vec());
+    }
+
+    #[test]
+    fn test_most_work_wins() {
+        let spec = ChainSpec {
+            retarget_interval: 2,
+            target_block_time: 10,
+            ..test_spec()
         };
+        let base = Blockchain::new(&spec, 0, 256);
+
+        // Fast blocks push difficulty up, so fewer blocks carry more work
+        let heavy = spaced_chain(&base, 4, 1);
+        let light = spaced_chain(&base, 5, 10);
+        assert!(light.len() > heavy.len());
+        assert!(heavy.total_work() > light.total_work());
+
+        let mut node = Node::new(light.clone());
+
+        let report = node.resolve_chain_conflict(&heavy).unwrap();
+        assert!(node.blockchain == heavy);
+        assert_eq!(report.removed, light.chain[1..].to_vec());
+        assert_eq!(report.added, heavy.chain[1..].to_vec());
+
+        let report = node.resolve_chain_conflict(&light).unwrap();
+        assert!(report.is_empty());
+        assert!(node.blockchain == heavy);
+    }
+
+    #[test]
+    fn test_equal_work_tie_break() {
+        let base = Blockchain::new(&test_spec(), 0, 256);
+
+        let first = spaced_chain(&base, 3, 1);
+        let second = spaced_chain(&base, 3, 2);
+        assert_eq!(first.total_work(), second.total_work());
+
+        let (lower, higher) = if first.tip_hash() < second.tip_hash() {
+            (first, second)
+        } else {
+            (second, first)
+        };
+
+        let mut node = Node::new(higher.clone());
+        assert_eq!(node.resolve_chain_conflict(&lower).unwrap().added.len(), 2);
+        assert!(node.blockchain == lower);
+
+        let mut node = Node::new(lower.clone());
+        assert!(node.resolve_chain_conflict(&higher).unwrap().is_empty());
+        assert!(node.blockchain == lower);
+    }
+
+    #[test]
+    fn test_out_of_order_blocks() {
+        let chain = generate_blockchain();
+        let tip = chain.tip_hash().to_string();
+        let mut node = Node::new(chain);
+
+        let first = mine(&tip, vec![], 4);
+        let second = mine(&first.hash, vec![], 4);
+        let third = mine(&second.hash, vec![], 4);
+
+        assert_eq!(
+            node.receive_block(third.clone()).unwrap(),
+            BlockOutcome::Orphaned {
+                missing: second.hash.clone()
+            }
+        );
+        assert_eq!(
+            node.receive_block(second.clone()).unwrap(),
+            BlockOutcome::Orphaned {
+                missing: first.hash.clone()
+            }
+        );
+        assert_eq!(
+            node.receive_block(third.clone()).unwrap(),
+            BlockOutcome::Duplicate
+        );
+        assert_eq!(node.blockchain.len(), 3);
+
+        assert_eq!(
+            node.receive_block(first.clone()).unwrap(),
+            BlockOutcome::Connected(vec![first.hash, second.hash, third.hash.clone()])
+        );
+        assert_eq!(node.blockchain.len(), 6);
+        assert_eq!(node.blockchain.tip_hash(), third.hash);
+        assert!(node.orphans.is_empty());
+        assert!(node.blockchain.is_valid());
+
+        let mut forged = mine("unknown parent", vec![], 4);
+        forged.header.nonce += 1;
+        assert!(matches!(
+            node.receive_block(forged),
+            Err(BlockError::HashMismatch { .. })
+        ));
+        assert!(node.orphans.is_empty());
+    }
 
-        node.resolve_chain_conflict(&chain);
+    #[test]
+    fn test_side_branch() {
+        let base = Blockchain::new(&test_spec(), 0, 256);
+        let chain = spaced_chain(&base, 3, 30);
+        let mut node = Node::new(chain.clone());
 
+        // A fork off genesis that is a block short only starts a branch
+        let start = base.chain[0].header.time();
+        let side = mine_at(base.tip_hash(), vec![], 4, start + Duration::from_secs(20));
+        assert_eq!(node.receive_block(side).unwrap(), BlockOutcome::SideBranch);
         assert!(node.blockchain == chain);
 
+        // A competing tip of the same work wins on the lower hash
+        let rival = mine_at(
+            &chain.chain[1].hash,
+            vec![],
+            4,
+            start + Duration::from_secs(45),
+        );
+        let rival_wins = rival.hash.as_str() < chain.tip_hash();
+        match node.receive_block(rival.clone()).unwrap() {
+            BlockOutcome::Reorganized(report) => {
+                assert!(rival_wins);
+                assert_eq!(report.removed, chain.chain[2..].to_vec());
+                assert_eq!(report.added, vec![rival.clone()]);
+                assert_eq!(node.blockchain.tip_hash(), rival.hash);
+            }
+            BlockOutcome::SideBranch => assert!(!rival_wins),
+            other => panic!("rival block was {:?}", other),
+        }
+
+        // Still checked against its parent
+        let forged = mine_at(&chain.chain[1].hash, vec![], 5, start);
+        assert!(matches!(
+            node.receive_block(forged),
+            Err(BlockError::DifficultyMismatch { .. })
+        ));
+
+        let mut tampered = mine_at(&chain.chain[1].hash, vec![], 4, start);
+        tampered.header.nonce += 1;
+        assert!(matches!(
+            node.receive_block(tampered),
+            Err(BlockError::HashMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_mempool_follows_chain() {
+        let alice = Keypair::generate_ed25519();
+        let base = Blockchain::new(&funded_spec(&alice, 100), 0, 256);
+        let mut node = Node::new(base.clone());
+
+        let tx = Transaction::new_signed(&alice, String::from("Bob"), 10, 1).unwrap();
+        node.submit_transaction(tx.clone()).unwrap();
+        assert!(node.submit_transaction(tx.clone()).is_err());
+
+        // A block from the network that includes the transaction evicts it
+        let block = mine(base.tip_hash(), vec![tx.clone()], 4);
+        node.receive_block(block).unwrap();
+        assert!(node.mempool.is_empty());
+
+        // Reorganising onto a heavier chain without it puts it back
+        let heavier = spaced_chain(&base, 3, 30);
+        let report = node.resolve_chain_conflict(&heavier).unwrap();
+        assert_eq!(report.removed.len(), 1);
+        assert_eq!(node.mempool.transactions(), vec![tx.clone()]);
+
+        node.mine("Miner").unwrap();
+        assert!(node.mempool.is_empty());
+        assert_eq!(node.balance("Bob"), 10);
+    }
+
+    #[test]
+    fn test_restart_from_store() {
+        let dir = temp_dir("node-store");
+        let base = Blockchain::new(&test_spec(), 0, 256);
+
+        let (store, stored) = BlockStore::open(&dir).unwrap();
+        let mut node = Node::with_store(base.clone(), store, stored).unwrap();
+        node.mine("Miner").unwrap();
+        let block = mine(node.blockchain.tip_hash(), vec![], 4);
+        node.receive_block(block).unwrap();
+        let mined = node.blockchain.clone();
+        drop(node);
+
+        let (store, stored) = BlockStore::open(&dir).unwrap();
+        assert_eq!(stored, mined.chain);
+        let mut node = Node::with_store(base.clone(), store, stored).unwrap();
+        assert!(node.blockchain == mined);
+        assert!(node.take_store_errors().is_empty());
+        drop(node);
+
+        // A reorg rewrites the stored chain from the fork point
+        let heavier = spaced_chain(&base, 4, 1);
+        let (store, stored) = BlockStore::open(&dir).unwrap();
+        let mut node = Node::with_store(base.clone(), store, stored).unwrap();
+        node.resolve_chain_conflict(&heavier).unwrap();
+        drop(node);
+        let (_, stored) = BlockStore::open(&dir).unwrap();
+        assert_eq!(stored, heavier.chain);
+
+        // A store that fell behind, as after a failed write, is caught up
+        let (store, stored) = BlockStore::open(&dir).unwrap();
+        let mut node = Node::with_store(base.clone(), store, stored).unwrap();
+        node.store.as_mut().unwrap().truncate(2).unwrap();
+        let block = mine(node.blockchain.tip_hash(), vec![], 4);
+        node.receive_block(block).unwrap();
+        let caught_up = node.blockchain.clone();
+        drop(node);
+        let (_, stored) = BlockStore::open(&dir).unwrap();
+        assert_eq!(stored, caught_up.chain);
+
+        // A stored block that no longer connects is dropped and reported
+        let (mut store, _) = BlockStore::open(&dir).unwrap();
+        store.append(&mine("unknown", vec![], 4)).unwrap();
+        drop(store);
+        let (store, stored) = BlockStore::open(&dir).unwrap();
+        let mut node = Node::with_store(base.clone(), store, stored).unwrap();
+        assert!(node.blockchain == caught_up);
+        let height = caught_up.len();
+        assert!(matches!(
+            node.take_store_errors().as_slice(),
+            [StorageError::InvalidBlock { height: at, .. }] if *at == height
+        ));
+        assert!(node.take_store_errors().is_empty());
+        drop(node);
+
+        // Another chain spec does not accept the stored chain
+        let other = Blockchain::new(&ChainSpec::dev(), 0, 256);
+        let (store, stored) = BlockStore::open(&dir).unwrap();
+        assert!(matches!(
+            Node::with_store(other, store, stored),
+            Err(StorageError::GenesisMismatch { .. })
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,113 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::block::Block;
+
+pub const DEFAULT_MAX_ORPHANS: usize = 128;
+
+/// Blocks whose parent we have not seen yet, keyed by the `prev_hash` they
+/// are waiting for. Holds at most `capacity` blocks, dropping the oldest first.
+pub struct OrphanPool {
+    by_prev: HashMap<String, Vec<Block>>,
+    // Insertion order as (prev_hash, hash), oldest first
+    order: VecDeque<(String, String)>,
+    len: usize,
+    capacity: usize,
+}
+
+impl OrphanPool {
+    pub fn new(capacity: usize) -> Self {
+        OrphanPool {
+            by_prev: HashMap::new(),
+            order: VecDeque::new(),
+            len: 0,
+            capacity,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.by_prev
+            .values()
+            .any(|blocks| blocks.iter().any(|block| block.hash == hash))
+    }
+
+    /// Returns false if the block is already pooled.
+    pub fn insert(&mut self, block: Block) -> bool {
+        if self.capacity == 0 || self.contains(&block.hash) {
+            return false;
+        }
+
+        while self.len >= self.capacity {
+            self.evict_oldest();
+        }
+
+        self.order
+            .push_back((block.header.prev_hash.clone(), block.hash.clone()));
+        self.by_prev
+            .entry(block.header.prev_hash.clone())
+            .or_default()
+            .push(block);
+        self.len += 1;
+        true
+    }
+
+    /// Removes and returns every pooled block whose parent is `hash`.
+    pub fn take_children(&mut self, hash: &str) -> Vec<Block> {
+        let children = self.by_prev.remove(hash).unwrap_or_default();
+        self.order.retain(|(prev_hash, _)| prev_hash != hash);
+        self.len -= children.len();
+        children
+    }
+
+    fn evict_oldest(&mut self) {
+        let (prev_hash, hash) = match self.order.pop_front() {
+            Some(oldest) => oldest,
+            None => return,
+        };
+
+        if let Some(siblings) = self.by_prev.get_mut(&prev_hash) {
+            siblings.retain(|block| block.hash != hash);
+            if siblings.is_empty() {
+                self.by_prev.remove(&prev_hash);
+            }
+            self.len -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::tests::mine;
+    use crate::orphans::OrphanPool;
+
+    #[test]
+    fn test_orphan_pool() {
+        let parent = mine("", vec![], 4);
+        let first = mine(&parent.hash, vec![], 4);
+        let second = mine(&parent.hash, vec![], 4);
+        let grandchild = mine(&first.hash, vec![], 4);
+
+        let mut pool = OrphanPool::new(2);
+        assert!(pool.insert(first.clone()));
+        assert!(!pool.insert(first.clone()));
+        assert!(pool.insert(second.clone()));
+        assert_eq!(pool.len(), 2);
+
+        // Full, so the oldest orphan makes room
+        assert!(pool.insert(grandchild.clone()));
+        assert_eq!(pool.len(), 2);
+        assert!(!pool.contains(&first.hash));
+
+        assert_eq!(pool.take_children(&parent.hash), vec![second]);
+        assert_eq!(pool.take_children(&first.hash), vec![grandchild]);
+        assert!(pool.is_empty());
+        assert!(pool.take_children(&parent.hash).is_empty());
+    }
+}
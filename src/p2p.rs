@@ -1,98 +1,476 @@
+use derive_more::Display;
 use libp2p::{
-    floodsub,
-    floodsub::{Floodsub, FloodsubEvent},
+    core::upgrade,
+    gossipsub::{
+        Gossipsub, GossipsubConfigBuilder, GossipsubEvent, GossipsubMessage, IdentTopic,
+        MessageAcceptance, MessageAuthenticity, MessageId,
+    },
+    identity,
     mdns::{Mdns, MdnsEvent},
-    swarm::{NetworkBehaviourEventProcess, Swarm},
-    NetworkBehaviour, PeerId,
+    mplex,
+    multiaddr::Protocol,
+    noise::{Keypair, NoiseConfig, X25519Spec},
+    request_response::{
+        ProtocolSupport, RequestResponse, RequestResponseConfig, RequestResponseEvent,
+        RequestResponseMessage,
+    },
+    swarm::{toggle::Toggle, NetworkBehaviourEventProcess, Swarm, SwarmBuilder},
+    tcp::TokioTcpConfig,
+    Multiaddr, NetworkBehaviour, PeerId, Transport,
 };
-use serde::{Deserialize, Serialize};
+use log::{info, warn};
+use sha2::{Digest, Sha256};
 use std::collections::HashSet;
-use tokio::sync::mpsc;
+use std::iter;
+use std::time::{Duration, Instant};
+use tokio::spawn;
 
-use crate::{block::Block, blockchain::Blockchain, node::Node};
+use crate::{
+    block::Block,
+    error::Error,
+    mempool::MempoolError,
+    node::{BlockOutcome, Node},
+    protocol::Message,
+    reputation::{Misbehaviour, PeerScores},
+    sync::{respond, ChainSync, Requests, SyncCodec, SyncProtocol, MAX_SYNC_MESSAGE_SIZE},
+    transaction::Transaction,
+};
+
+/// How often bootstrap peers are dialed again while disconnected.
+pub const REDIAL_INTERVAL: Duration = Duration::from_secs(30);
+/// How long a mining node waits between one block search and the next.
+pub const MINING_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Display)]
+pub enum ConfigError {
+    #[display(fmt = "invalid multiaddr {}", _0)]
+    InvalidAddress(String),
+    #[display(fmt = "bootstrap address {} does not end in /p2p/<peer id>", _0)]
+    MissingPeerId(String),
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Where the node listens and how it finds peers.
+#[derive(Clone, Debug)]
+pub struct NetworkConfig {
+    pub listen_addr: Multiaddr,
+    /// Peers dialed at startup and again while disconnected
+    pub bootstrap: Vec<(PeerId, Multiaddr)>,
+    /// Find peers on the local network by multicast
+    pub mdns: bool,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            listen_addr: "/ip4/0.0.0.0/tcp/0"
+                .parse()
+                .expect("default address is valid"),
+            bootstrap: vec![],
+            mdns: true,
+        }
+    }
+}
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct ChainResponse {
-    pub blockchain: Blockchain,
-    pub receiver: String,
+impl NetworkConfig {
+    /// Reads `ECHAIN_LISTEN_ADDR`, `ECHAIN_BOOTSTRAP`, a comma separated list
+    /// of multiaddrs, and `ECHAIN_MDNS`, which turns mDNS off when set to
+    /// `off`, `false` or `0`. Anything unset keeps its default.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let mut config = NetworkConfig::default();
+        if let Ok(addr) = std::env::var("ECHAIN_LISTEN_ADDR") {
+            config.listen_addr = parse_addr(&addr)?;
+        }
+        if let Ok(list) = std::env::var("ECHAIN_BOOTSTRAP") {
+            config.bootstrap = parse_bootstrap(&list)?;
+        }
+        if let Ok(mdns) = std::env::var("ECHAIN_MDNS") {
+            config.mdns = !matches!(mdns.as_str(), "off" | "false" | "0");
+        }
+        Ok(config)
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct LocalChainRequest {
-    pub from_peer_id: String,
+fn parse_addr(addr: &str) -> Result<Multiaddr, ConfigError> {
+    addr.trim()
+        .parse()
+        .map_err(|_| ConfigError::InvalidAddress(addr.to_string()))
 }
 
-pub enum EventType {
-    LocalChainResponse(ChainResponse),
-    Init,
-    Cli,
+/// Splits a comma separated list of `/ip4/../tcp/../p2p/<peer id>`
+/// addresses into peers and the addresses they are dialed at.
+pub fn parse_bootstrap(list: &str) -> Result<Vec<(PeerId, Multiaddr)>, ConfigError> {
+    list.split(',')
+        .filter(|addr| !addr.trim().is_empty())
+        .map(|addr| {
+            let mut dial_addr = parse_addr(addr)?;
+            match dial_addr.pop() {
+                Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash)
+                    .map(|peer| (peer, dial_addr))
+                    .map_err(|_| ConfigError::MissingPeerId(addr.to_string())),
+                _ => Err(ConfigError::MissingPeerId(addr.to_string())),
+            }
+        })
+        .collect()
+}
+
+/// Sets up the swarm of a node: TCP with noise and mplex, carrying the
+/// behaviour below.
+pub async fn swarm_factory(
+    id_keys: &identity::Keypair,
+    node: Node,
+    network: &NetworkConfig,
+) -> Result<SwarmBuilder<AppBehaviour>, Error> {
+    let peer_id = PeerId::from(id_keys.public());
+
+    let auth_keys = Keypair::<X25519Spec>::new()
+        .into_authentic(id_keys)
+        .map_err(|e| Error::Network(e.to_string()))?;
+
+    let transp = TokioTcpConfig::new()
+        .upgrade(upgrade::Version::V1)
+        .authenticate(NoiseConfig::xx(auth_keys).into_authenticated())
+        .multiplex(mplex::MplexConfig::new())
+        .boxed();
+
+    let behaviour = AppBehaviour::new(id_keys.clone(), node, network).await?;
+
+    Ok(
+        SwarmBuilder::new(transp, behaviour, peer_id).executor(Box::new(|fut| {
+            spawn(fut);
+        })),
+    )
 }
 
 #[derive(NetworkBehaviour)]
 pub struct AppBehaviour {
-    pub floodsub: Floodsub,
-    pub mdns: Mdns,
-    #[behaviour(ignore)]
-    pub response_sender: mpsc::UnboundedSender<ChainResponse>,
+    pub gossipsub: Gossipsub,
+    pub mdns: Toggle<Mdns>,
+    pub sync: RequestResponse<SyncCodec>,
     #[behaviour(ignore)]
     pub node: Node,
     #[behaviour(ignore)]
+    pub chain_sync: ChainSync,
+    #[behaviour(ignore)]
+    pub scores: PeerScores,
+    #[behaviour(ignore)]
     pub peer_id: PeerId,
     #[behaviour(ignore)]
-    pub blockchain_topic: floodsub::Topic,
+    pub bootstrap: Vec<(PeerId, Multiaddr)>,
+    #[behaviour(ignore)]
+    pub blockchain_topic: IdentTopic,
     #[behaviour(ignore)]
-    pub transaction_topic: floodsub::Topic,
+    pub transaction_topic: IdentTopic,
+    /// Whether the node keeps mining blocks, see `MiningJob`
+    #[behaviour(ignore)]
+    pub mining: bool,
+}
+
+/// Names a gossiped message by the hash of its bytes, so a message relayed
+/// by several peers is only handled once. The hash a block claims is not
+/// used, a forged copy could take the id of the real block.
+fn message_id(message: &GossipsubMessage) -> MessageId {
+    MessageId::from(format!("{:x}", Sha256::digest(&message.data)))
 }
 
 impl AppBehaviour {
     pub async fn new(
-        peer_id: PeerId,
+        id_keys: identity::Keypair,
         node: Node,
-        response_sender: mpsc::UnboundedSender<ChainResponse>,
-    ) -> Self {
-        let mut behaviour = Self {
-            node: node,
-            peer_id: peer_id,
-            floodsub: Floodsub::new(peer_id),
-            mdns: Mdns::new(Default::default())
+        config: &NetworkConfig,
+    ) -> Result<Self, Error> {
+        let peer_id = PeerId::from(id_keys.public());
+        // Messages are only relayed once the node has checked them, see
+        // `inject_event` for `GossipsubEvent`
+        let gossip_config = GossipsubConfigBuilder::default()
+            .validate_messages()
+            .message_id_fn(message_id)
+            .max_transmit_size(MAX_SYNC_MESSAGE_SIZE)
+            .build()
+            .map_err(|e| Error::Network(e.to_string()))?;
+
+        let mdns = if config.mdns {
+            let mdns = Mdns::new(Default::default())
                 .await
-                .expect("can create mdns"),
+                .map_err(|e| Error::Network(e.to_string()))?;
+            Some(mdns)
+        } else {
+            None
+        };
+
+        let mut behaviour = Self {
+            node,
+            chain_sync: ChainSync::new(),
+            scores: PeerScores::new(),
+            peer_id,
+            bootstrap: config.bootstrap.clone(),
+            gossipsub: Gossipsub::new(MessageAuthenticity::Signed(id_keys), gossip_config)
+                .map_err(|e| Error::Network(e.to_string()))?,
+            mdns: Toggle::from(mdns),
+            sync: RequestResponse::new(
+                SyncCodec,
+                iter::once((SyncProtocol, ProtocolSupport::Full)),
+                RequestResponseConfig::default(),
+            ),
+
+            blockchain_topic: IdentTopic::new("blockchain"),
+            transaction_topic: IdentTopic::new("transactions"),
+            mining: false,
+        };
+
+        for topic in [&behaviour.blockchain_topic, &behaviour.transaction_topic] {
+            behaviour
+                .gossipsub
+                .subscribe(topic)
+                .map_err(|e| Error::Network(format!("{:?}", e)))?;
+        }
+        for (peer, addr) in &config.bootstrap {
+            behaviour.sync.add_address(peer, addr.clone());
+        }
+
+        Ok(behaviour)
+    }
+
+    /// Tells `peer` where our chain stands and asks the same of it. Sync
+    /// starts from there if either chain is behind.
+    pub fn request_status(&mut self, peer: &PeerId) {
+        let status = self.node.status();
+        self.sync.send_request(peer, Message::GetStatus(status));
+    }
+
+    fn send_requests(&mut self, requests: Requests) {
+        for (peer, request) in requests {
+            self.sync.send_request(&peer, request);
+        }
+    }
+
+    /// Lowers the score of `peer` if it caused `error` and, once it is
+    /// banned, stops syncing with it. The swarm disconnects it in
+    /// `update_bans`.
+    fn penalize(&mut self, peer: PeerId, error: &Error) {
+        let misbehaviour = match Misbehaviour::of(error) {
+            Some(misbehaviour) => misbehaviour,
+            None => return,
+        };
+        let banned = self.scores.penalize(peer, misbehaviour, Instant::now());
+        warn!(
+            "{} from {}, score {}",
+            misbehaviour,
+            peer,
+            self.scores.score(&peer)
+        );
+        if banned {
+            let requests = self.chain_sync.on_failure(&peer);
+            self.send_requests(requests);
+        }
+    }
+
+    pub fn publish(&mut self, topic: IdentTopic, message: &Message) {
+        if let Err(e) = self.gossipsub.publish(topic, message.encode()) {
+            warn!("can not publish {}: {:?}", message.kind(), e);
+        }
+    }
+
+    /// Checks a gossiped message and decides whether it is passed on. An
+    /// error means the message is rejected.
+    fn handle_gossip(&mut self, source: &PeerId, data: &[u8]) -> Result<MessageAcceptance, Error> {
+        match Message::decode(data)? {
+            Message::NewBlock(block) => match self.node.receive_block(*block)? {
+                BlockOutcome::Connected(_) => Ok(MessageAcceptance::Accept),
+                BlockOutcome::Orphaned { missing } => {
+                    // Can not be checked until its parent is here
+                    info!("orphan block from {}, missing parent {}", source, missing);
+                    self.request_status(source);
+                    Ok(MessageAcceptance::Ignore)
+                }
+                BlockOutcome::Reorganized(report) => {
+                    info!(
+                        "chain reorganised by block from {}: {} blocks out, {} blocks in",
+                        source,
+                        report.removed.len(),
+                        report.added.len()
+                    );
+                    Ok(MessageAcceptance::Accept)
+                }
+                BlockOutcome::SideBranch => {
+                    // The sender may be further along that branch
+                    self.request_status(source);
+                    Ok(MessageAcceptance::Ignore)
+                }
+                BlockOutcome::Duplicate => Ok(MessageAcceptance::Ignore),
+            },
+            Message::NewTransaction(tx) => match self.node.submit_transaction(*tx) {
+                Ok(_) => Ok(MessageAcceptance::Accept),
+                Err(MempoolError::Duplicate(_)) | Err(MempoolError::Full) => {
+                    Ok(MessageAcceptance::Ignore)
+                }
+                Err(e) => Err(e.into()),
+            },
+            // Everything else is only sent on request
+            other => Err(Error::UnexpectedMessage(other.kind())),
+        }
+    }
+
+    /// Feeds a response to a sync request into the sync, returning the
+    /// requests to send next.
+    fn handle_response(&mut self, peer: PeerId, response: Message) -> Result<Requests, Error> {
+        match response {
+            Message::Status(status) => Ok(self.chain_sync.on_status(&self.node, peer, status)),
+            Message::Headers(headers) => self.chain_sync.on_headers(&self.node, peer, headers),
+            Message::Blocks(blocks) => {
+                let (requests, report) = self.chain_sync.on_blocks(&mut self.node, peer, blocks)?;
+                if let Some(report) = report {
+                    info!(
+                        "chain reorganised by {}: {} blocks out, {} blocks in",
+                        peer,
+                        report.removed.len(),
+                        report.added.len()
+                    );
+                }
+                Ok(requests)
+            }
+            other => Err(Error::UnexpectedMessage(other.kind())),
+        }
+    }
+
+    /// Admits a locally created transaction to the mempool and gossips it.
+    pub fn submit_transaction(&mut self, tx: Transaction) -> Result<String, MempoolError> {
+        let message = Message::NewTransaction(Box::new(tx.clone()));
+        let id = self.node.submit_transaction(tx)?;
+        self.publish(self.transaction_topic.clone(), &message);
+        Ok(id)
+    }
 
-            blockchain_topic: floodsub::Topic::new("blockchain"),
-            transaction_topic: floodsub::Topic::new("transactions"),
-            response_sender,
+    /// Signs a payment of `amount` to `to` with `id_keys`, the key of this
+    /// node, and submits it. Without a `fee`, pays the rate recent blocks
+    /// paid.
+    pub fn send_coins(
+        &mut self,
+        id_keys: &identity::Keypair,
+        to: String,
+        amount: u64,
+        fee: Option<u64>,
+    ) -> Result<Transaction, Error> {
+        let fee = match fee {
+            Some(fee) => fee,
+            None => {
+                // Size the fee from a draft
+                let draft = Transaction::new_signed(id_keys, to.clone(), amount, 0)?;
+                self.node.blockchain.estimate_fee(draft.size())
+            }
         };
-        
-        behaviour
-            .floodsub
-            .subscribe(behaviour.blockchain_topic.clone());
-        
-        behaviour
+        let tx = Transaction::new_signed(id_keys, to, amount, fee)?;
+        self.submit_transaction(tx.clone())?;
+        Ok(tx)
+    }
+
+    /// Connects a block mined off the event loop, see `MiningJob`, and
+    /// announces it. A block the tip moved away from meanwhile is dropped.
+    // https://www.oreilly.com/library/view/mastering-bitcoin/9781491902639/ch08.html
+    pub fn add_mined(&mut self, block: Block) -> Result<BlockOutcome, Error> {
+        let message = Message::NewBlock(Box::new(block.clone()));
+        let outcome = self.node.receive_block(block)?;
+        if let BlockOutcome::Connected(_) | BlockOutcome::Reorganized(_) = outcome {
+            self.publish(self.blockchain_topic.clone(), &message);
+        }
+        Ok(outcome)
     }
 }
 
 // incoming event handler
-impl NetworkBehaviourEventProcess<FloodsubEvent> for AppBehaviour {
-    fn inject_event(&mut self, event: FloodsubEvent) {
-        if let FloodsubEvent::Message(msg) = event {
-            if let Ok(resp) = serde_json::from_slice::<ChainResponse>(&msg.data) {
-                if resp.receiver == self.peer_id.to_string() {
+impl NetworkBehaviourEventProcess<GossipsubEvent> for AppBehaviour {
+    fn inject_event(&mut self, event: GossipsubEvent) {
+        if let GossipsubEvent::Message {
+            propagation_source,
+            message_id,
+            message,
+        } = event
+        {
+            let acceptance = if self.scores.is_banned(&propagation_source)
+                || !self.scores.on_message(propagation_source, Instant::now())
+            {
+                MessageAcceptance::Ignore
+            } else {
+                match self.handle_gossip(&propagation_source, &message.data) {
+                    Ok(acceptance) => {
+                        if let MessageAcceptance::Accept = acceptance {
+                            self.scores.reward(propagation_source, Instant::now());
+                        }
+                        acceptance
+                    }
+                    // Not relayed either way, but only a peer at fault
+                    // gets it counted against it by gossipsub
+                    Err(e) if Misbehaviour::of(&e).is_none() => {
+                        info!("ignored message from {}: {}", propagation_source, e);
+                        MessageAcceptance::Ignore
+                    }
+                    Err(e) => {
+                        warn!("rejected message from {}: {}", propagation_source, e);
+                        self.penalize(propagation_source, &e);
+                        MessageAcceptance::Reject
+                    }
+                }
+            };
+            let _ = self.gossipsub.report_message_validation_result(
+                &message_id,
+                &propagation_source,
+                acceptance,
+            );
+        }
+    }
+}
 
-                    self.node.resolve_chain_conflict(&resp.blockchain);
+impl NetworkBehaviourEventProcess<RequestResponseEvent<Message, Message>> for AppBehaviour {
+    fn inject_event(&mut self, event: RequestResponseEvent<Message, Message>) {
+        match event {
+            RequestResponseEvent::Message { peer, .. } if self.scores.is_banned(&peer) => {}
+            RequestResponseEvent::Message {
+                peer,
+                message:
+                    RequestResponseMessage::Request {
+                        request, channel, ..
+                    },
+            } => {
+                // Dropping the channel leaves a spammer without an answer
+                if !self.scores.on_message(peer, Instant::now()) {
+                    return;
                 }
-            } else if let Ok(resp) = serde_json::from_slice::<LocalChainRequest>(&msg.data) {
-                let peer_id = resp.from_peer_id;
-                if self.peer_id.to_string() == peer_id {
-                    if let Err(e) = self.response_sender.send(ChainResponse {
-                        blockchain: self.node.blockchain.clone(),
-                        receiver: msg.source.to_string(),
-                    }) {
-                        println!("error sending response via channel, {} \r\n", e);
+                match respond(&self.node, &request) {
+                    Ok(response) => {
+                        let _ = self.sync.send_response(channel, response);
+                    }
+                    Err(e) => {
+                        warn!("bad request from {}: {}", peer, e);
+                        self.penalize(peer, &e);
                     }
                 }
-            } else if let Ok(block) = serde_json::from_slice::<Block>(&msg.data) {
-                self.node.blockchain.add_block(block);
+
+                // A peer telling us its status may be ahead of us
+                if let Message::GetStatus(status) = request {
+                    let requests = self.chain_sync.on_status(&self.node, peer, status);
+                    self.send_requests(requests);
+                }
             }
+            RequestResponseEvent::Message {
+                peer,
+                message: RequestResponseMessage::Response { response, .. },
+            } => match self.handle_response(peer, response) {
+                Ok(requests) => self.send_requests(requests),
+                Err(e) => {
+                    warn!("sync with {} failed: {}", peer, e);
+                    self.penalize(peer, &e);
+                }
+            },
+            RequestResponseEvent::OutboundFailure { peer, error, .. } => {
+                warn!("sync request to {} failed: {:?}", peer, error);
+                let requests = self.chain_sync.on_failure(&peer);
+                self.send_requests(requests);
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                warn!("sync request from {} failed: {:?}", peer, error)
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
         }
     }
 }
@@ -101,27 +479,202 @@ impl NetworkBehaviourEventProcess<MdnsEvent> for AppBehaviour {
     fn inject_event(&mut self, event: MdnsEvent) {
         match event {
             MdnsEvent::Discovered(discovered_list) => {
-                for (peer, _addr) in discovered_list {
-                    self.floodsub.add_node_to_partial_view(peer);
+                let mut discovered = HashSet::new();
+                // Gossip starts once the status request below connects
+                for (peer, addr) in discovered_list {
+                    self.sync.add_address(&peer, addr);
+                    discovered.insert(peer);
+                }
+                // Catch up with every newly found peer
+                for peer in discovered {
+                    if self.scores.is_banned(&peer) {
+                        continue;
+                    }
+                    self.request_status(&peer);
                 }
             }
             MdnsEvent::Expired(expired_list) => {
-                for (peer, _addr) in expired_list {
-                    if !self.mdns.has_node(&peer) {
-                        self.floodsub.remove_node_from_partial_view(&peer);
-                    }
+                for (peer, addr) in expired_list {
+                    self.sync.remove_address(&peer, &addr);
                 }
             }
         }
     }
 }
 
-pub fn get_list_peers(swarm: &Swarm<AppBehaviour>) -> Vec<String> {
+/// Asks every bootstrap peer we are not connected to for its status, which
+/// dials it first.
+pub fn dial_bootstrap(swarm: &mut Swarm<AppBehaviour>) {
+    let peers: Vec<PeerId> = swarm
+        .behaviour()
+        .bootstrap
+        .iter()
+        .map(|(peer, _)| *peer)
+        .filter(|peer| !swarm.is_connected(peer) && !swarm.behaviour().scores.is_banned(peer))
+        .collect();
+    for peer in peers {
+        swarm.behaviour_mut().request_status(&peer);
+    }
+}
+
+/// Disconnects and bans the peers that just lost all reputation, and lets
+/// back those whose ban ran out.
+pub fn update_bans(swarm: &mut Swarm<AppBehaviour>) {
+    let scores = &mut swarm.behaviour_mut().scores;
+    let banned = scores.take_new_bans();
+    let expired = scores.expire_bans(Instant::now());
+
+    for peer in banned {
+        warn!("banning {}", peer);
+        swarm.ban_peer_id(peer);
+    }
+    for peer in expired {
+        info!("ban of {} lifted", peer);
+        swarm.unban_peer_id(peer);
+    }
+}
 
-    let nodes = swarm.behaviour().mdns.discovered_nodes();
-    let mut unique_peers = HashSet::new();
-    for peer in nodes {
-        unique_peers.insert(peer);
+pub fn get_list_peers(swarm: &Swarm<AppBehaviour>) -> Vec<String> {
+    let behaviour = swarm.behaviour();
+    let mut unique_peers: HashSet<&PeerId> = HashSet::new();
+    if let Some(mdns) = behaviour.mdns.as_ref() {
+        unique_peers.extend(mdns.discovered_nodes());
     }
+    unique_peers.extend(behaviour.gossipsub.all_peers().map(|(peer, _)| peer));
     unique_peers.iter().map(|p| p.to_string()).collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::block::tests::generate_blocks;
+    use crate::blockchain::tests::{generate_blockchain, test_spec};
+    use crate::blockchain::Blockchain;
+    use crate::node::Node;
+    use crate::p2p::{
+        dial_bootstrap, message_id, parse_bootstrap, swarm_factory, AppBehaviour, ConfigError,
+        NetworkConfig,
+    };
+    use crate::protocol::Message;
+    use libp2p::futures::StreamExt;
+    use libp2p::gossipsub::{GossipsubMessage, IdentTopic};
+    use libp2p::identity::Keypair;
+    use libp2p::swarm::{Swarm, SwarmEvent};
+    use libp2p::PeerId;
+    use std::time::Duration;
+    use tokio::{select, time};
+
+    /// Swarm listening on a free localhost port, without mDNS so that only
+    /// the `bootstrap` peers are found.
+    async fn local_swarm(
+        chain: Blockchain,
+        bootstrap: Vec<(PeerId, libp2p::Multiaddr)>,
+    ) -> Swarm<AppBehaviour> {
+        let network = NetworkConfig {
+            listen_addr: "/ip4/127.0.0.1/tcp/0".parse().unwrap(),
+            bootstrap,
+            mdns: false,
+        };
+        let keys = Keypair::generate_ed25519();
+        let mut swarm = swarm_factory(&keys, Node::new(chain), &network)
+            .await
+            .unwrap()
+            .build();
+        Swarm::listen_on(&mut swarm, network.listen_addr.clone()).unwrap();
+        swarm
+    }
+
+    fn gossiped(data: Vec<u8>, sequence_number: u64) -> GossipsubMessage {
+        GossipsubMessage {
+            source: Some(PeerId::random()),
+            data,
+            sequence_number: Some(sequence_number),
+            topic: IdentTopic::new("blockchain").hash(),
+        }
+    }
+
+    #[test]
+    fn test_message_id() {
+        let blocks = generate_blocks();
+        let encoded = Message::NewBlock(Box::new(blocks[0].clone())).encode();
+
+        // The same block published by two peers is one message
+        let first = message_id(&gossiped(encoded.clone(), 1));
+        let second = message_id(&gossiped(encoded, 7));
+        assert_eq!(first, second);
+
+        let other = Message::NewBlock(Box::new(blocks[1].clone())).encode();
+        assert_ne!(message_id(&gossiped(other, 1)), first);
+
+        // A tampered copy claiming the same hash does not shadow the block
+        let mut tampered = blocks[0].clone();
+        tampered.transactions.pop();
+        let tampered = Message::NewBlock(Box::new(tampered)).encode();
+        assert_ne!(message_id(&gossiped(tampered, 1)), first);
+
+        let garbage = message_id(&gossiped(b"not a message".to_vec(), 1));
+        assert_eq!(garbage, message_id(&gossiped(b"not a message".to_vec(), 2)));
+    }
+
+    #[test]
+    fn test_parse_bootstrap() {
+        let first = PeerId::random();
+        let second = PeerId::random();
+        let list = format!(
+            "/ip4/127.0.0.1/tcp/4001/p2p/{}, /ip4/10.0.0.2/tcp/4001/p2p/{}",
+            first, second
+        );
+
+        let peers = parse_bootstrap(&list).unwrap();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[0].0, first);
+        assert_eq!(peers[0].1, "/ip4/127.0.0.1/tcp/4001".parse().unwrap());
+        assert_eq!(peers[1].0, second);
+        assert!(parse_bootstrap("").unwrap().is_empty());
+
+        assert!(matches!(
+            parse_bootstrap("/ip4/127.0.0.1/tcp/4001"),
+            Err(ConfigError::MissingPeerId(_))
+        ));
+        assert!(matches!(
+            parse_bootstrap("127.0.0.1:4001"),
+            Err(ConfigError::InvalidAddress(_))
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_bootstrap_and_sync() {
+        let chain = generate_blockchain();
+        let mut seed = local_swarm(chain.clone(), vec![]).await;
+        let address = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = seed.select_next_some().await {
+                break address;
+            }
+        };
+
+        let fresh = Blockchain::new(&test_spec(), 3, 256);
+        let bootstrap = vec![(*seed.local_peer_id(), address)];
+        let mut joining = local_swarm(fresh, bootstrap).await;
+        dial_bootstrap(&mut joining);
+
+        // Sync is handled inside the behaviours, without a swarm event to wake
+        // the loop, so the chain is checked on a tick as well
+        let mut check = time::interval(Duration::from_millis(50));
+        let synced = time::timeout(Duration::from_secs(20), async {
+            let mut connected = false;
+            while !connected || joining.behaviour().node.blockchain != chain {
+                select! {
+                    _ = check.tick() => {}
+                    _ = seed.select_next_some() => {}
+                    event = joining.select_next_some() => {
+                        if let SwarmEvent::ConnectionEstablished { peer_id, .. } = event {
+                            connected |= peer_id == *seed.local_peer_id();
+                        }
+                    }
+                }
+            }
+        })
+        .await;
+        assert!(synced.is_ok(), "nodes did not connect and sync");
+        assert!(joining.is_connected(seed.local_peer_id()));
+    }
+}
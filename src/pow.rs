@@ -0,0 +1,105 @@
+/// Number of leading zero bits in a hex encoded hash. Invalid hex digits end
+/// the count, so a malformed hash never looks like it carries extra work.
+pub fn leading_zero_bits(hash: &str) -> u32 {
+    let mut bits = 0;
+    for c in hash.chars() {
+        match c.to_digit(16) {
+            Some(0) => bits += 4,
+            Some(digit) => return bits + digit.leading_zeros() - 28,
+            None => return bits,
+        }
+    }
+    bits
+}
+
+/// Proof-of-work check: the hash must start with at least `difficulty` zero bits.
+pub fn meets_difficulty(hash: &str, difficulty: u32) -> bool {
+    leading_zero_bits(hash) >= difficulty
+}
+
+/// Expected number of hashes needed to find a block at `difficulty`.
+pub fn work(difficulty: u32) -> u128 {
+    1u128 << difficulty.min(127)
+}
+
+/// Lowest difficulty retargeting may fall to.
+pub const MIN_DIFFICULTY: u32 = 1;
+/// Most bits difficulty may move by in a single retarget.
+pub const MAX_RETARGET_STEP: u32 = 2;
+
+/// Difficulty for the next retarget window, given how long the last window
+/// took (`actual`) against how long it should have taken (`expected`).
+///
+/// One bit of difficulty doubles or halves the expected block time, so the
+/// difficulty moves a bit at a time while the timespan is off by more than a
+/// factor of sqrt(2). Timestamps come from miners, so `actual` is clamped to
+/// within a factor of four of `expected`, which is all `MAX_RETARGET_STEP`
+/// bits can make up for. Only integer maths is used so every node agrees.
+pub fn retarget(difficulty: u32, actual: u128, expected: u128) -> u32 {
+    let mut expected = expected.max(1);
+    let mut actual = actual.clamp((expected / 4).max(1), expected.saturating_mul(4));
+    // Scaled down until the squares below can not overflow
+    while expected > 1 << 56 {
+        expected >>= 1;
+        actual >>= 1;
+    }
+    let mut actual = actual.max(1);
+    let mut difficulty = difficulty;
+
+    for _ in 0..MAX_RETARGET_STEP {
+        if 2 * actual * actual < expected * expected {
+            difficulty = difficulty.saturating_add(1);
+            actual *= 2;
+        } else if actual * actual > 2 * expected * expected && difficulty > MIN_DIFFICULTY {
+            difficulty -= 1;
+            expected *= 2;
+        } else {
+            break;
+        }
+    }
+
+    difficulty
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pow::{leading_zero_bits, meets_difficulty, retarget, MIN_DIFFICULTY};
+
+    #[test]
+    fn test_leading_zero_bits() {
+        assert_eq!(leading_zero_bits("ffff"), 0);
+        assert_eq!(leading_zero_bits("7fff"), 1);
+        assert_eq!(leading_zero_bits("1fff"), 3);
+        assert_eq!(leading_zero_bits("0fff"), 4);
+        assert_eq!(leading_zero_bits("008f"), 8);
+        assert_eq!(leading_zero_bits("0000"), 16);
+        assert_eq!(leading_zero_bits("0x00"), 4);
+        assert_eq!(leading_zero_bits(""), 0);
+
+        assert!(meets_difficulty("00ab", 8));
+        assert!(meets_difficulty("003b", 10));
+        assert!(!meets_difficulty("004b", 10));
+    }
+
+    #[test]
+    fn test_retarget() {
+        assert_eq!(retarget(10, 1000, 1000), 10);
+        assert_eq!(retarget(10, 1300, 1000), 10);
+        assert_eq!(retarget(10, 800, 1000), 10);
+
+        assert_eq!(retarget(10, 500, 1000), 11);
+        assert_eq!(retarget(10, 2000, 1000), 9);
+
+        // Clamped to MAX_RETARGET_STEP bits either way
+        assert_eq!(retarget(10, 1, 1000), 12);
+        assert_eq!(retarget(10, 0, 1000), 12);
+        assert_eq!(retarget(10, 100_000, 1000), 8);
+
+        assert_eq!(retarget(MIN_DIFFICULTY, 100_000, 1000), MIN_DIFFICULTY);
+
+        // Timespans from forged timestamps neither overflow nor go further
+        assert_eq!(retarget(10, u128::MAX, 1000), 8);
+        assert_eq!(retarget(10, u128::MAX, u128::MAX), 10);
+        assert_eq!(retarget(10, 0, u128::MAX), 12);
+    }
+}
@@ -0,0 +1,173 @@
+use derive_more::Display;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    block::{Block, BlockHeader},
+    transaction::Transaction,
+};
+
+/// Version of the message format below. Peers drop messages of any other.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Debug, Display)]
+pub enum ProtocolError {
+    #[display(fmt = "malformed message: {}", _0)]
+    Malformed(serde_json::Error),
+    #[display(fmt = "unsupported protocol version {}", _0)]
+    UnsupportedVersion(u32),
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// Where a peer's chain stands, exchanged before any headers are fetched.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChainStatus {
+    pub genesis_hash: String,
+    /// Height of the tip, genesis being 0
+    pub height: usize,
+    pub tip_hash: String,
+    #[serde(with = "decimal")]
+    pub total_work: u128,
+}
+
+/// Carries a `u128` as a decimal string, as JSON numbers inside the
+/// flattened envelope can not hold one.
+mod decimal {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &u128, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&value.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u128, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetHeaders {
+    /// Block locator of the requesting chain, see `Blockchain::locator`
+    pub locator: Vec<String>,
+    pub max: usize,
+}
+
+/// Everything peers say to each other, tagged with its kind so it is never
+/// mistaken for another. Status, headers and blocks are requested point to
+/// point over the sync protocol, new blocks and transactions are gossiped.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
+pub enum Message {
+    /// Carries the requester's own status, answered with `Status`
+    GetStatus(ChainStatus),
+    Status(ChainStatus),
+    GetHeaders(GetHeaders),
+    Headers(Vec<BlockHeader>),
+    /// Hashes of the wanted blocks, answered with `Blocks`
+    GetBlocks(Vec<String>),
+    Blocks(Vec<Block>),
+    NewBlock(Box<Block>),
+    NewTransaction(Box<Transaction>),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope<M> {
+    version: u32,
+    #[serde(flatten)]
+    message: M,
+}
+
+// Just enough of an envelope to check its version before the rest
+#[derive(Deserialize)]
+struct VersionOnly {
+    version: u32,
+}
+
+impl Message {
+    /// Name of the variant, as tagged on the wire.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Message::GetStatus(_) => "get_status",
+            Message::Status(_) => "status",
+            Message::GetHeaders(_) => "get_headers",
+            Message::Headers(_) => "headers",
+            Message::GetBlocks(_) => "get_blocks",
+            Message::Blocks(_) => "blocks",
+            Message::NewBlock(_) => "new_block",
+            Message::NewTransaction(_) => "new_transaction",
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(&Envelope {
+            version: PROTOCOL_VERSION,
+            message: self,
+        })
+        .expect("message is serializable")
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let VersionOnly { version } =
+            serde_json::from_slice(bytes).map_err(ProtocolError::Malformed)?;
+        if version != PROTOCOL_VERSION {
+            return Err(ProtocolError::UnsupportedVersion(version));
+        }
+
+        let envelope: Envelope<Message> =
+            serde_json::from_slice(bytes).map_err(ProtocolError::Malformed)?;
+        Ok(envelope.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::tests::generate_blocks;
+    use crate::protocol::{ChainStatus, Message, ProtocolError, PROTOCOL_VERSION};
+
+    #[test]
+    fn test_envelope() {
+        let block = generate_blocks().remove(0);
+        let bytes = Message::NewBlock(Box::new(block.clone())).encode();
+        match Message::decode(&bytes).unwrap() {
+            Message::NewBlock(decoded) => assert_eq!(*decoded, block),
+            other => panic!("decoded as {:?}", other),
+        }
+
+        let status = ChainStatus {
+            genesis_hash: String::from("genesis"),
+            height: 3,
+            tip_hash: block.hash.clone(),
+            total_work: u128::MAX,
+        };
+        match Message::decode(&Message::Status(status.clone()).encode()).unwrap() {
+            Message::Status(decoded) => assert_eq!(decoded, status),
+            other => panic!("decoded as {:?}", other),
+        }
+
+        // A bare block, as sent before the envelope, is not a message
+        let legacy = serde_json::to_vec(&block).unwrap();
+        assert!(matches!(
+            Message::decode(&legacy),
+            Err(ProtocolError::Malformed(_))
+        ));
+
+        let future = format!(
+            r#"{{"version": {}, "type": "new_block", "payload": {{}}}}"#,
+            PROTOCOL_VERSION + 1
+        );
+        assert!(matches!(
+            Message::decode(future.as_bytes()),
+            Err(ProtocolError::UnsupportedVersion(_))
+        ));
+
+        let unknown = format!(
+            r#"{{"version": {}, "type": "gossip", "payload": null}}"#,
+            PROTOCOL_VERSION
+        );
+        assert!(matches!(
+            Message::decode(unknown.as_bytes()),
+            Err(ProtocolError::Malformed(_))
+        ));
+    }
+}
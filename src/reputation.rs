@@ -0,0 +1,292 @@
+use derive_more::Display;
+use libp2p::PeerId;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::{block::BlockError, error::Error, mempool::MempoolError};
+
+/// A peer is banned once its score drops to this.
+pub const BAN_THRESHOLD: i32 = -100;
+pub const BAN_DURATION: Duration = Duration::from_secs(10 * 60);
+/// Gossip and requests a peer may send per window before it is spamming.
+pub const RATE_LIMIT: usize = 200;
+pub const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, Display, PartialEq)]
+pub enum Misbehaviour {
+    #[display(fmt = "invalid block")]
+    InvalidBlock,
+    #[display(fmt = "invalid transaction")]
+    InvalidTransaction,
+    /// Undecodable, or a kind that has no business where it was sent
+    #[display(fmt = "malformed message")]
+    Malformed,
+    #[display(fmt = "too many messages")]
+    Spam,
+}
+
+impl Misbehaviour {
+    /// What a peer did wrong to cause `error`, if anything. Only errors
+    /// that are wrong on their own terms count. A block off our tip or a tx
+    /// our state already spent may be honest, sent before the peer saw what
+    /// we saw.
+    pub fn of(error: &Error) -> Option<Misbehaviour> {
+        match error {
+            Error::Block(error) | Error::InvalidChains { other: error, .. } => {
+                Misbehaviour::of_block(error)
+            }
+            Error::Transaction(_) => Some(Misbehaviour::InvalidTransaction),
+            Error::Mempool(MempoolError::InvalidTransaction(_))
+            | Error::Mempool(MempoolError::Coinbase) => Some(Misbehaviour::InvalidTransaction),
+            Error::Protocol(_) | Error::UnexpectedMessage(_) => Some(Misbehaviour::Malformed),
+            _ => None,
+        }
+    }
+
+    /// A block is checked against its own parent, so anything but a parent
+    /// we do not have on the tip or a clock running ahead of ours is the
+    /// sender's fault.
+    fn of_block(error: &BlockError) -> Option<Misbehaviour> {
+        match error {
+            BlockError::PrevHashMismatch { .. } | BlockError::TimestampInFuture { .. } => None,
+            _ => Some(Misbehaviour::InvalidBlock),
+        }
+    }
+
+    pub fn penalty(&self) -> i32 {
+        match self {
+            Misbehaviour::InvalidBlock => 50,
+            Misbehaviour::Malformed => 25,
+            Misbehaviour::InvalidTransaction => 20,
+            Misbehaviour::Spam => 10,
+        }
+    }
+}
+
+struct PeerRecord {
+    score: i32,
+    window_start: Instant,
+    messages: usize,
+}
+
+/// Reputation of every peer we heard from. Scores start at 0, drop with
+/// every misbehaviour and recover by one with every useful message, up to 0
+/// again. A peer at `BAN_THRESHOLD` is banned for `BAN_DURATION`.
+pub struct PeerScores {
+    peers: HashMap<PeerId, PeerRecord>,
+    // Banned peers and when their ban ends
+    banned: HashMap<PeerId, Instant>,
+    // Banned since the last `take_new_bans`
+    new_bans: Vec<PeerId>,
+}
+
+impl PeerScores {
+    pub fn new() -> Self {
+        PeerScores {
+            peers: HashMap::new(),
+            banned: HashMap::new(),
+            new_bans: vec![],
+        }
+    }
+
+    fn record(&mut self, peer: PeerId, now: Instant) -> &mut PeerRecord {
+        self.peers.entry(peer).or_insert(PeerRecord {
+            score: 0,
+            window_start: now,
+            messages: 0,
+        })
+    }
+
+    pub fn score(&self, peer: &PeerId) -> i32 {
+        self.peers.get(peer).map_or(0, |record| record.score)
+    }
+
+    pub fn is_banned(&self, peer: &PeerId) -> bool {
+        self.banned.contains_key(peer)
+    }
+
+    /// Counts a message `peer` sent us unasked. Returns false, and penalises
+    /// the peer, once it sent more than `RATE_LIMIT` in the current window.
+    pub fn on_message(&mut self, peer: PeerId, now: Instant) -> bool {
+        let record = self.record(peer, now);
+        if now.duration_since(record.window_start) >= RATE_WINDOW {
+            record.window_start = now;
+            record.messages = 0;
+        }
+        record.messages += 1;
+
+        if record.messages > RATE_LIMIT {
+            self.penalize(peer, Misbehaviour::Spam, now);
+            return false;
+        }
+        true
+    }
+
+    /// Lowers the score of `peer`, banning it at `BAN_THRESHOLD`. Returns
+    /// true if it got banned.
+    pub fn penalize(&mut self, peer: PeerId, misbehaviour: Misbehaviour, now: Instant) -> bool {
+        if self.is_banned(&peer) {
+            return false;
+        }
+
+        let record = self.record(peer, now);
+        record.score -= misbehaviour.penalty();
+        if record.score > BAN_THRESHOLD {
+            return false;
+        }
+
+        self.banned.insert(peer, now + BAN_DURATION);
+        self.new_bans.push(peer);
+        true
+    }
+
+    pub fn reward(&mut self, peer: PeerId, now: Instant) {
+        let record = self.record(peer, now);
+        record.score = (record.score + 1).min(0);
+    }
+
+    /// Peers banned since the last call, for the swarm to disconnect.
+    pub fn take_new_bans(&mut self) -> Vec<PeerId> {
+        std::mem::take(&mut self.new_bans)
+    }
+
+    /// Lifts the bans that ran out by `now` and returns those peers, who
+    /// start over with a clean score.
+    pub fn expire_bans(&mut self, now: Instant) -> Vec<PeerId> {
+        let expired: Vec<PeerId> = self
+            .banned
+            .iter()
+            .filter(|(_, until)| **until <= now)
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in &expired {
+            self.banned.remove(peer);
+            self.peers.remove(peer);
+        }
+        expired
+    }
+
+    /// Banned peers with the time left on their ban, shortest first.
+    pub fn banned(&self, now: Instant) -> Vec<(PeerId, Duration)> {
+        let mut banned: Vec<(PeerId, Duration)> = self
+            .banned
+            .iter()
+            .map(|(peer, until)| (*peer, until.saturating_duration_since(now)))
+            .collect();
+        banned.sort_by_key(|(_, left)| *left);
+        banned
+    }
+}
+
+impl Default for PeerScores {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::BlockError;
+    use crate::error::Error;
+    use crate::mempool::MempoolError;
+    use crate::reputation::{Misbehaviour, PeerScores, BAN_DURATION, RATE_LIMIT, RATE_WINDOW};
+    use crate::state::StateError;
+    use crate::transaction::TransactionError;
+    use libp2p::PeerId;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_ban_and_expiry() {
+        let mut scores = PeerScores::new();
+        let peer = PeerId::random();
+        let now = Instant::now();
+
+        assert!(!scores.penalize(peer, Misbehaviour::InvalidBlock, now));
+        // Good behaviour wins back some of the lost score
+        scores.reward(peer, now);
+        assert_eq!(scores.score(&peer), -49);
+        assert!(!scores.penalize(peer, Misbehaviour::Malformed, now));
+        assert!(!scores.is_banned(&peer));
+        assert!(scores.take_new_bans().is_empty());
+
+        assert!(scores.penalize(peer, Misbehaviour::InvalidBlock, now));
+        assert!(scores.is_banned(&peer));
+        assert_eq!(scores.take_new_bans(), vec![peer]);
+        assert!(scores.take_new_bans().is_empty());
+        assert_eq!(scores.banned(now), vec![(peer, BAN_DURATION)]);
+
+        // Nothing changes while the ban lasts
+        assert!(!scores.penalize(peer, Misbehaviour::InvalidBlock, now));
+        assert!(scores.expire_bans(now + BAN_DURATION / 2).is_empty());
+
+        assert_eq!(scores.expire_bans(now + BAN_DURATION), vec![peer]);
+        assert!(!scores.is_banned(&peer));
+        assert_eq!(scores.score(&peer), 0);
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mut scores = PeerScores::new();
+        let peer = PeerId::random();
+        let now = Instant::now();
+
+        for _ in 0..RATE_LIMIT {
+            assert!(scores.on_message(peer, now));
+        }
+        assert!(!scores.on_message(peer, now + Duration::from_secs(1)));
+        assert_eq!(scores.score(&peer), -Misbehaviour::Spam.penalty());
+
+        // A new window starts the count over
+        assert!(scores.on_message(peer, now + RATE_WINDOW));
+    }
+
+    #[test]
+    fn test_misbehaviour_of_error() {
+        let invalid = Error::Block(BlockError::MissingCoinbase);
+        assert_eq!(Misbehaviour::of(&invalid), Some(Misbehaviour::InvalidBlock));
+        let unexpected = Error::UnexpectedMessage("get_status");
+        assert_eq!(Misbehaviour::of(&unexpected), Some(Misbehaviour::Malformed));
+
+        // Known or unwanted, but not wrong
+        let duplicate = Error::Mempool(MempoolError::Duplicate(String::from("tx")));
+        assert_eq!(Misbehaviour::of(&duplicate), None);
+        assert_eq!(Misbehaviour::of(&Error::Mempool(MempoolError::Full)), None);
+
+        // Honest peers send these when they saw a different chain than we did
+        let fork = Error::Block(BlockError::PrevHashMismatch {
+            expected: String::from("tip"),
+            found: String::from("parent"),
+        });
+        assert_eq!(Misbehaviour::of(&fork), None);
+        let spent = Error::Mempool(MempoolError::StateViolation(StateError::Replayed(
+            String::from("tx"),
+        )));
+        assert_eq!(Misbehaviour::of(&spent), None);
+        let early = Error::Block(BlockError::TimestampInFuture {
+            timestamp: 2,
+            limit: 1,
+        });
+        assert_eq!(Misbehaviour::of(&early), None);
+
+        // A block is checked against its own parent, so replaying a
+        // transaction there is never honest
+        let replayed = Error::Block(BlockError::StateViolation {
+            index: 1,
+            reason: StateError::Replayed(String::from("tx")),
+        });
+        assert_eq!(
+            Misbehaviour::of(&replayed),
+            Some(Misbehaviour::InvalidBlock)
+        );
+        let easier = Error::Block(BlockError::DifficultyMismatch {
+            expected: 5,
+            found: 4,
+        });
+        assert_eq!(Misbehaviour::of(&easier), Some(Misbehaviour::InvalidBlock));
+        let forged = Error::Block(BlockError::InvalidTransaction {
+            index: 1,
+            reason: TransactionError::InvalidSignature,
+        });
+        assert_eq!(Misbehaviour::of(&forged), Some(Misbehaviour::InvalidBlock));
+    }
+}
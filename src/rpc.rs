@@ -0,0 +1,705 @@
+use derive_more::Display;
+use libp2p::{identity, swarm::Swarm};
+use log::warn;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::spawn;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    error::Error,
+    keystore,
+    p2p::{self, AppBehaviour},
+    transaction::Transaction,
+};
+
+/// Where the node takes RPC calls unless told otherwise. Local only.
+pub const DEFAULT_RPC_ADDR: &str = "127.0.0.1:7070";
+/// Longest request line read before the connection is dropped.
+pub const MAX_REQUEST_SIZE: u64 = 1024 * 1024;
+const TOKEN_FILE: &str = "rpc.token";
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+/// The call was understood but the node could not carry it out
+pub const SERVER_ERROR: i64 = -32000;
+/// The connection did not open with an `auth` call carrying the token
+pub const UNAUTHORIZED: i64 = -32001;
+
+/// Error object of a JSON-RPC 2.0 response.
+#[derive(Clone, Debug, Display, PartialEq, Serialize, Deserialize)]
+#[display(fmt = "{} (code {})", message, code)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn new<M: ToString>(code: i64, message: M) -> Self {
+        RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+impl From<Error> for RpcError {
+    fn from(error: Error) -> Self {
+        RpcError::new(SERVER_ERROR, error)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Request {
+    jsonrpc: String,
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Response {
+    jsonrpc: String,
+    id: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+impl Response {
+    fn new(id: Value, result: Result<Value, RpcError>) -> Self {
+        let (result, error) = match result {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+        Response {
+            jsonrpc: String::from("2.0"),
+            id,
+            result,
+            error,
+        }
+    }
+}
+
+/// A request handed to the node, which answers through `reply`.
+pub struct Call {
+    pub method: String,
+    pub params: Value,
+    pub reply: oneshot::Sender<Result<Value, RpcError>>,
+}
+
+/// The token clients authenticate with, kept in `dir`. A random one is
+/// generated and written on first use, readable by the owner only.
+pub fn load_or_create_token<P: AsRef<Path>>(dir: P) -> io::Result<String> {
+    let path = dir.as_ref().join(TOKEN_FILE);
+    match read_token(&path) {
+        Ok(token) => Ok(token),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let token: String = rand::random::<[u8; 32]>()
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            fs::create_dir_all(&dir)?;
+            keystore::write_private(&path, token.as_bytes())?;
+            Ok(token)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// The token the node keeping its data in `dir` created, for clients,
+/// which never create one themselves.
+pub fn load_token<P: AsRef<Path>>(dir: P) -> Result<String, RpcError> {
+    let path = dir.as_ref().join(TOKEN_FILE);
+    read_token(&path).map_err(|e| {
+        let message = match e.kind() {
+            io::ErrorKind::NotFound => {
+                format!("node not running or token missing at {}", path.display())
+            }
+            _ => format!("can not read token at {}: {}", path.display(), e),
+        };
+        RpcError::new(UNAUTHORIZED, message)
+    })
+}
+
+fn read_token(path: &Path) -> io::Result<String> {
+    let token = fs::read_to_string(path)?;
+    keystore::warn_if_exposed(path);
+    Ok(token.trim().to_string())
+}
+
+/// Compares in time independent of where the tokens differ.
+fn token_matches(token: &str, given: &str) -> bool {
+    !token.is_empty()
+        && token.len() == given.len()
+        && token
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[derive(Deserialize)]
+struct AuthParams {
+    token: String,
+}
+
+/// Takes JSON-RPC 2.0 requests, one per line, on every connection to
+/// `listener` and passes them on to `calls`. Each response is written back
+/// as a line of its own.
+///
+/// A connection has to open with an `auth` call whose `token` param is
+/// `token`. It is answered and dropped on anything else.
+pub async fn serve(listener: TcpListener, token: String, calls: mpsc::UnboundedSender<Call>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let token = token.clone();
+                let calls = calls.clone();
+                spawn(async move {
+                    if let Err(e) = handle_connection(stream, &token, calls).await {
+                        warn!("rpc connection failed: {}", e);
+                    }
+                });
+            }
+            Err(e) => warn!("can not accept rpc connection: {}", e),
+        }
+    }
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    token: &str,
+    calls: mpsc::UnboundedSender<Call>,
+) -> io::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut reader = BufReader::new(read);
+    let mut authenticated = false;
+    loop {
+        let mut line = String::new();
+        let read = (&mut reader)
+            .take(MAX_REQUEST_SIZE)
+            .read_line(&mut line)
+            .await?;
+        if read == 0 {
+            return Ok(());
+        }
+
+        let oversized = read as u64 >= MAX_REQUEST_SIZE && !line.ends_with('\n');
+        let response = if oversized {
+            let error = RpcError::new(INVALID_REQUEST, "request is too large");
+            Response::new(Value::Null, Err(error))
+        } else {
+            answer(&line, token, &mut authenticated, &calls).await
+        };
+
+        let mut bytes = serde_json::to_vec(&response)?;
+        bytes.push(b'\n');
+        write.write_all(&bytes).await?;
+        if oversized || !authenticated {
+            return Ok(());
+        }
+    }
+}
+
+async fn answer(
+    line: &str,
+    token: &str,
+    authenticated: &mut bool,
+    calls: &mpsc::UnboundedSender<Call>,
+) -> Response {
+    let request: Request = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(e) => return Response::new(Value::Null, Err(RpcError::new(PARSE_ERROR, e))),
+    };
+    if request.jsonrpc != "2.0" {
+        let error = RpcError::new(INVALID_REQUEST, "only JSON-RPC 2.0 is supported");
+        return Response::new(request.id, Err(error));
+    }
+    if request.method == "auth" {
+        let result = params::<AuthParams>(request.params).and_then(|params| {
+            *authenticated = token_matches(token, &params.token);
+            if *authenticated {
+                Ok(Value::Bool(true))
+            } else {
+                Err(RpcError::new(UNAUTHORIZED, "wrong token"))
+            }
+        });
+        return Response::new(request.id, result);
+    }
+    if !*authenticated {
+        let error = RpcError::new(UNAUTHORIZED, "call auth with the token first");
+        return Response::new(request.id, Err(error));
+    }
+
+    let (reply, result) = oneshot::channel();
+    let call = Call {
+        method: request.method,
+        params: request.params,
+        reply,
+    };
+    let result = match calls.send(call) {
+        Ok(()) => result
+            .await
+            .unwrap_or_else(|_| Err(RpcError::new(SERVER_ERROR, "node dropped the call"))),
+        Err(_) => Err(RpcError::new(SERVER_ERROR, "node is shutting down")),
+    };
+    Response::new(request.id, result)
+}
+
+/// Calls `method` on the node listening at `addr`, authenticating with
+/// `token` first.
+pub async fn call(
+    addr: SocketAddr,
+    token: &str,
+    method: &str,
+    params: Value,
+) -> Result<Value, Error> {
+    let stream = TcpStream::connect(addr).await?;
+    let (read, mut write) = stream.into_split();
+    let mut reader = BufReader::new(read);
+
+    let auth = Request {
+        jsonrpc: String::from("2.0"),
+        id: json!(0),
+        method: String::from("auth"),
+        params: json!({ "token": token }),
+    };
+    let request = Request {
+        jsonrpc: String::from("2.0"),
+        id: json!(1),
+        method: method.to_string(),
+        params,
+    };
+    let mut bytes = vec![];
+    for request in [auth, request] {
+        bytes.extend(serde_json::to_vec(&request).expect("request is serializable"));
+        bytes.push(b'\n');
+    }
+    write.write_all(&bytes).await?;
+
+    let mut result = Value::Null;
+    for _ in 0..2 {
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+        let response: Response = serde_json::from_str(&line)
+            .map_err(|e| RpcError::new(PARSE_ERROR, format!("bad response: {}", e)))?;
+        if let Some(error) = response.error {
+            return Err(error.into());
+        }
+        result = response.result.unwrap_or(Value::Null);
+    }
+    Ok(result)
+}
+
+#[derive(Deserialize)]
+struct SendParams {
+    to: String,
+    amount: u64,
+    fee: Option<u64>,
+}
+
+/// A block is looked up by `hash` if given, else by `height`.
+#[derive(Deserialize)]
+struct BlockParams {
+    hash: Option<String>,
+    height: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct TxParams {
+    id: String,
+}
+
+/// Without an `address`, the balance of the node's own wallet.
+#[derive(Deserialize)]
+struct BalanceParams {
+    address: Option<String>,
+}
+
+fn params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e))
+}
+
+fn to_value<T: Serialize>(value: &T) -> Result<Value, RpcError> {
+    serde_json::to_value(value).map_err(|e| RpcError::new(SERVER_ERROR, e))
+}
+
+/// Answers `call` from the node behind `swarm`. Transactions are signed
+/// with `id_keys`, the key of the node.
+///
+/// A `mine_block` call is handed back unanswered. Mining takes a while, so
+/// the caller runs it off the event loop, see `MiningJob`, and answers the
+/// call with `answer_mined` once the block is in.
+pub fn dispatch(
+    swarm: &mut Swarm<AppBehaviour>,
+    id_keys: &identity::Keypair,
+    call: Call,
+) -> Option<Call> {
+    if call.method == "mine_block" {
+        return Some(call);
+    }
+    let result = handle(swarm, id_keys, &call.method, call.params);
+    let _ = call.reply.send(result);
+    None
+}
+
+/// Answers a `mine_block` call with the hash and height of the block mined
+/// for it, or with what kept it from being mined.
+pub fn answer_mined(call: Call, mined: Result<(String, usize), RpcError>) {
+    let result = mined.map(|(hash, height)| json!({ "hash": hash, "height": height }));
+    let _ = call.reply.send(result);
+}
+
+fn handle(
+    swarm: &mut Swarm<AppBehaviour>,
+    id_keys: &identity::Keypair,
+    method: &str,
+    params_value: Value,
+) -> Result<Value, RpcError> {
+    match method {
+        "chain_height" => Ok(json!(swarm.behaviour().node.blockchain.len() - 1)),
+        "chain_blocks" => to_value(&swarm.behaviour().node.blockchain.chain),
+        "block_get" => {
+            let BlockParams { hash, height } = params(params_value)?;
+            let blockchain = &swarm.behaviour().node.blockchain;
+            let height = match (hash, height) {
+                (Some(hash), _) => blockchain.height_of(&hash),
+                (None, Some(height)) => Some(height),
+                (None, None) => {
+                    return Err(RpcError::new(INVALID_PARAMS, "hash or height is needed"))
+                }
+            };
+            match height.and_then(|height| blockchain.chain.get(height)) {
+                Some(block) => Ok(json!({ "height": height, "block": block })),
+                None => Ok(Value::Null),
+            }
+        }
+        "chain_verify" => {
+            let blockchain = &swarm.behaviour().node.blockchain;
+            let error = blockchain.validate().err().map(|e| e.to_string());
+            Ok(json!({
+                "valid": error.is_none(),
+                "height": blockchain.len().saturating_sub(1),
+                "error": error,
+            }))
+        }
+        "peers_list" => to_value(&p2p::get_list_peers(swarm)),
+        "mempool_list" => to_value(&swarm.behaviour().node.mempool.transactions()),
+        "tx_get" => {
+            let TxParams { id } = params(params_value)?;
+            let node = &swarm.behaviour().node;
+            // Pending ones first, they are few
+            if let Some(tx) = node
+                .mempool
+                .transactions()
+                .into_iter()
+                .find(|tx| tx.id() == id)
+            {
+                return Ok(json!({ "tx": tx, "block": null, "height": null }));
+            }
+            for (height, block) in node.blockchain.chain.iter().enumerate().rev() {
+                if let Some(tx) = block.transactions.iter().find(|tx| tx.id() == id) {
+                    return Ok(json!({ "tx": tx, "block": block.hash, "height": height }));
+                }
+            }
+            Ok(Value::Null)
+        }
+        "balance" => {
+            let BalanceParams { address } = params(params_value)?;
+            let behaviour = swarm.behaviour();
+            let address = address.unwrap_or_else(|| behaviour.peer_id.to_string());
+            let balance = behaviour.node.balance(&address);
+            Ok(json!({ "address": address, "balance": balance }))
+        }
+        "tx_submit" => {
+            let tx: Transaction = params(params_value)?;
+            let id = swarm
+                .behaviour_mut()
+                .submit_transaction(tx)
+                .map_err(Error::from)?;
+            Ok(json!({ "id": id }))
+        }
+        "tx_send" => {
+            let SendParams { to, amount, fee } = params(params_value)?;
+            let tx = swarm.behaviour_mut().send_coins(id_keys, to, amount, fee)?;
+            Ok(json!({ "id": tx.id(), "fee": tx.fee }))
+        }
+        "mining_start" | "mining_stop" => {
+            swarm.behaviour_mut().mining = method == "mining_start";
+            Ok(json!({ "mining": swarm.behaviour().mining }))
+        }
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("no method {}", method),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blockchain::tests::test_spec;
+    use crate::blockchain::Blockchain;
+    use crate::error::Error;
+    use crate::miner::MiningJob;
+    use crate::node::Node;
+    use crate::p2p::{self, AppBehaviour, NetworkConfig};
+    use crate::rpc::{self, Call, METHOD_NOT_FOUND, PARSE_ERROR, TOKEN_FILE, UNAUTHORIZED};
+    use crate::storage::tests::temp_dir;
+    use crate::transaction::Transaction;
+    use libp2p::{identity::Keypair, swarm::Swarm};
+    use serde_json::{json, Value};
+    use std::fs;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::spawn;
+    use tokio::sync::{mpsc, oneshot};
+
+    /// A fresh node without mDNS, and its key.
+    async fn test_swarm() -> (Swarm<AppBehaviour>, Keypair) {
+        let keys = Keypair::generate_ed25519();
+        let node = Node::new(Blockchain::new(&test_spec(), 0, 256));
+        let network = NetworkConfig {
+            mdns: false,
+            ..NetworkConfig::default()
+        };
+        let swarm = p2p::swarm_factory(&keys, node, &network)
+            .await
+            .unwrap()
+            .build();
+        (swarm, keys)
+    }
+
+    fn dispatch(
+        swarm: &mut Swarm<AppBehaviour>,
+        keys: &Keypair,
+        method: &str,
+        params: Value,
+    ) -> Result<Value, rpc::RpcError> {
+        let (reply, mut result) = oneshot::channel();
+        let call = Call {
+            method: method.to_string(),
+            params,
+            reply,
+        };
+        assert!(rpc::dispatch(swarm, keys, call).is_none());
+        result.try_recv().unwrap()
+    }
+
+    /// Calls `mine_block`, which is handed back to be mined off the event
+    /// loop, and answers it the way the node does.
+    async fn mine_block(swarm: &mut Swarm<AppBehaviour>, keys: &Keypair) -> Value {
+        let (reply, mut result) = oneshot::channel();
+        let call = Call {
+            method: String::from("mine_block"),
+            params: json!({}),
+            reply,
+        };
+        let call = rpc::dispatch(swarm, keys, call).expect("mining is left to the caller");
+        assert!(result.try_recv().is_err());
+
+        let behaviour = swarm.behaviour();
+        let template = behaviour
+            .node
+            .block_template(&behaviour.peer_id.to_string())
+            .unwrap();
+        let block = MiningJob::start(template).finish().await.unwrap();
+        let hash = block.hash.clone();
+        swarm.behaviour_mut().add_mined(block).unwrap();
+        let height = swarm.behaviour().node.blockchain.height_of(&hash).unwrap();
+        rpc::answer_mined(call, Ok((hash, height)));
+        result.try_recv().unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (calls, mut received) = mpsc::unbounded_channel::<Call>();
+        spawn(rpc::serve(listener, String::from("secret"), calls));
+        spawn(async move {
+            while let Some(call) = received.recv().await {
+                let result = match call.method.as_str() {
+                    "echo" => Ok(call.params),
+                    _ => Err(rpc::RpcError::new(METHOD_NOT_FOUND, "no such method")),
+                };
+                let _ = call.reply.send(result);
+            }
+        });
+
+        let echoed = rpc::call(addr, "secret", "echo", json!({ "a": 1 }))
+            .await
+            .unwrap();
+        assert_eq!(echoed, json!({ "a": 1 }));
+        match rpc::call(addr, "secret", "nothing", json!({})).await {
+            Err(Error::Rpc(e)) => assert_eq!(e.code, METHOD_NOT_FOUND),
+            other => panic!("called unknown method: {:?}", other),
+        }
+        match rpc::call(addr, "guess", "echo", json!({})).await {
+            Err(Error::Rpc(e)) => assert_eq!(e.code, UNAUTHORIZED),
+            other => panic!("called with a wrong token: {:?}", other),
+        }
+
+        // Calls before auth are refused and the connection dropped
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+        write
+            .write_all(b"{\"jsonrpc\": \"2.0\", \"id\": 1, \"method\": \"echo\"}\n")
+            .await
+            .unwrap();
+        let response: Value =
+            serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(response["error"]["code"], json!(UNAUTHORIZED));
+        assert!(lines.next_line().await.unwrap().is_none());
+
+        // After auth a bad line is answered and the connection stays usable
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+        write
+            .write_all(
+                b"{\"jsonrpc\": \"2.0\", \"id\": 1, \"method\": \"auth\", \"params\": {\"token\": \"secret\"}}\n",
+            )
+            .await
+            .unwrap();
+        let response: Value =
+            serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(response["result"], json!(true));
+        write.write_all(b"not json\n").await.unwrap();
+        let response: Value =
+            serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(response["error"]["code"], json!(PARSE_ERROR));
+        write
+            .write_all(
+                b"{\"jsonrpc\": \"2.0\", \"id\": 7, \"method\": \"echo\", \"params\": [1]}\n",
+            )
+            .await
+            .unwrap();
+        let response: Value =
+            serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(
+            response,
+            json!({ "jsonrpc": "2.0", "id": 7, "result": [1] })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dispatch() {
+        let (mut swarm, keys) = test_swarm().await;
+        let verified = dispatch(&mut swarm, &keys, "chain_verify", json!({})).unwrap();
+        assert_eq!(
+            verified,
+            json!({ "valid": true, "height": 0, "error": null })
+        );
+        assert_eq!(
+            dispatch(&mut swarm, &keys, "mempool_list", json!({})).unwrap(),
+            json!([])
+        );
+
+        let mined = mine_block(&mut swarm, &keys).await;
+        assert_eq!(mined["height"], json!(1));
+        assert_eq!(
+            dispatch(&mut swarm, &keys, "chain_height", json!({})).unwrap(),
+            json!(1)
+        );
+        let by_height = dispatch(&mut swarm, &keys, "block_get", json!({ "height": 1 })).unwrap();
+        assert_eq!(by_height["block"]["hash"], mined["hash"]);
+        let by_hash = dispatch(
+            &mut swarm,
+            &keys,
+            "block_get",
+            json!({ "hash": mined["hash"] }),
+        );
+        assert_eq!(by_hash.unwrap(), by_height);
+        let missing = dispatch(&mut swarm, &keys, "block_get", json!({ "height": 9 }));
+        assert_eq!(missing.unwrap(), Value::Null);
+        let neither = dispatch(&mut swarm, &keys, "block_get", json!({}));
+        assert_eq!(neither.unwrap_err().code, rpc::INVALID_PARAMS);
+
+        // The reward of the mined block is the node's to spend
+        let own = dispatch(&mut swarm, &keys, "balance", json!({})).unwrap();
+        let reward = swarm.behaviour().node.blockchain.subsidy_at(1);
+        assert_eq!(own["balance"], json!(reward));
+        let coinbase = by_height["block"]["transactions"][0].clone();
+        let coinbase: Transaction = serde_json::from_value(coinbase).unwrap();
+        let found = dispatch(&mut swarm, &keys, "tx_get", json!({ "id": coinbase.id() }));
+        assert_eq!(found.unwrap()["height"], json!(1));
+
+        let sent = dispatch(
+            &mut swarm,
+            &keys,
+            "tx_send",
+            json!({ "to": "Bob", "amount": 10, "fee": 1 }),
+        )
+        .unwrap();
+        let pending = dispatch(&mut swarm, &keys, "tx_get", json!({ "id": sent["id"] })).unwrap();
+        assert_eq!(pending["block"], Value::Null);
+        assert_eq!(pending["tx"]["to"], json!("Bob"));
+        // Submitted again it is a duplicate
+        let resubmitted = dispatch(&mut swarm, &keys, "tx_submit", pending["tx"].clone());
+        assert_eq!(resubmitted.unwrap_err().code, rpc::SERVER_ERROR);
+        let missing = dispatch(&mut swarm, &keys, "tx_get", json!({ "id": "none" }));
+        assert_eq!(missing.unwrap(), Value::Null);
+
+        let sent = dispatch(&mut swarm, &keys, "tx_send", json!({ "to": "Bob" }));
+        assert_eq!(sent.unwrap_err().code, rpc::INVALID_PARAMS);
+        let unknown = dispatch(&mut swarm, &keys, "chain_delete", json!({}));
+        assert_eq!(unknown.unwrap_err().code, METHOD_NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_mining_switch() {
+        let (mut swarm, keys) = test_swarm().await;
+        let started = dispatch(&mut swarm, &keys, "mining_start", json!({})).unwrap();
+        assert_eq!(started, json!({ "mining": true }));
+        assert!(swarm.behaviour().mining);
+        let stopped = dispatch(&mut swarm, &keys, "mining_stop", json!({})).unwrap();
+        assert_eq!(stopped, json!({ "mining": false }));
+        assert!(!swarm.behaviour().mining);
+    }
+
+    #[test]
+    fn test_token_survives_restart() {
+        let dir = temp_dir("rpc-token");
+
+        // Clients only read it
+        let missing = rpc::load_token(&dir).unwrap_err();
+        assert_eq!(missing.code, UNAUTHORIZED);
+        assert!(!dir.join(TOKEN_FILE).exists());
+
+        let first = rpc::load_or_create_token(&dir).unwrap();
+        assert_eq!(first.len(), 64);
+        assert_eq!(rpc::load_or_create_token(&dir).unwrap(), first);
+        assert_eq!(rpc::load_token(&dir).unwrap(), first);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(dir.join(TOKEN_FILE))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        assert!(rpc::token_matches(&first, &first));
+        assert!(!rpc::token_matches(&first, &first[1..]));
+        assert!(!rpc::token_matches("", ""));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
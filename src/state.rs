@@ -0,0 +1,334 @@
+use derive_more::Display;
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    block::{Block, BlockError},
+    transaction::Transaction,
+};
+
+/// Reason a transaction can not be applied to the account state.
+#[derive(Clone, Display, PartialEq, Debug)]
+pub enum StateError {
+    #[display(fmt = "amount must be positive")]
+    ZeroAmount,
+    #[display(fmt = "{} sends to itself", _0)]
+    SelfTransfer(String),
+    #[display(fmt = "{} has {} coins but sends {}", address, balance, amount)]
+    InsufficientFunds {
+        address: String,
+        balance: u64,
+        amount: u64,
+    },
+    #[display(fmt = "transaction {} is already on the chain", _0)]
+    Replayed(String),
+    #[display(fmt = "coinbase claims {} but at most {} is allowed", claimed, allowed)]
+    ExcessiveCoinbase { allowed: u64, claimed: u64 },
+}
+
+impl std::error::Error for StateError {}
+
+/// Balances of every address, as left by replaying a chain from its genesis.
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct AccountState {
+    balances: HashMap<String, u64>,
+    // Ids of applied transactions, so a signed transaction is only spent once
+    applied: HashSet<String>,
+}
+
+impl AccountState {
+    /// State right after the genesis block. Its allocations are credited as
+    /// they are, without the checks regular transactions go through.
+    pub fn genesis(genesis: &Block) -> Self {
+        let mut state = AccountState::default();
+        for tx in &genesis.transactions {
+            *state.balances.entry(tx.to.clone()).or_default() += tx.amount;
+            state.applied.insert(tx.id());
+        }
+        state
+    }
+
+    pub fn balance(&self, address: &str) -> u64 {
+        self.balances.get(address).copied().unwrap_or(0)
+    }
+
+    pub fn check_transaction(&self, tx: &Transaction) -> Result<(), StateError> {
+        PendingState::new(self).check_transaction(tx)
+    }
+
+    /// Moves `amount` to the receiver. The sender also pays `fee`, which is
+    /// left for the block's coinbase to claim.
+    pub fn apply_transaction(&mut self, tx: &Transaction) -> Result<(), StateError> {
+        let mut pending = PendingState::new(self);
+        pending.apply_transaction(tx)?;
+        let changes = pending.into_changes();
+        self.commit(changes);
+        Ok(())
+    }
+
+    /// Applies every transaction of `block` in order, or none of them. The
+    /// first transaction must be a coinbase paying at most `subsidy` plus the
+    /// fees of the rest.
+    pub fn apply_block(&mut self, block: &Block, subsidy: u64) -> Result<(), BlockError> {
+        let (coinbase, txs) = match block.transactions.split_first() {
+            Some((coinbase, txs)) if coinbase.is_coinbase() => (coinbase, txs),
+            _ => return Err(BlockError::MissingCoinbase),
+        };
+
+        let mut pending = PendingState::new(self);
+        let mut fees: u64 = 0;
+        for (index, tx) in txs.iter().enumerate() {
+            pending
+                .apply_transaction(tx)
+                .map_err(|reason| BlockError::StateViolation {
+                    index: index + 1,
+                    reason,
+                })?;
+            fees = fees.saturating_add(tx.fee);
+        }
+
+        let allowed = subsidy.saturating_add(fees);
+        let id = coinbase.id();
+        let reason = if coinbase.amount > allowed {
+            Some(StateError::ExcessiveCoinbase {
+                allowed,
+                claimed: coinbase.amount,
+            })
+        } else if pending.is_applied(&id) {
+            Some(StateError::Replayed(id.clone()))
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            return Err(BlockError::StateViolation { index: 0, reason });
+        }
+
+        let balance = pending.balance(&coinbase.to);
+        pending
+            .balances
+            .insert(coinbase.to.clone(), balance + coinbase.amount);
+        pending.applied.insert(id);
+
+        let changes = pending.into_changes();
+        self.commit(changes);
+        Ok(())
+    }
+
+    fn commit(&mut self, (balances, applied): Changes) {
+        self.balances.extend(balances);
+        self.applied.extend(applied);
+    }
+}
+
+// Balances touched and ids applied by a `PendingState`
+type Changes = (HashMap<String, u64>, HashSet<String>);
+
+/// Transactions applied on top of an `AccountState` without changing it.
+/// Only the balances they touch are kept, so checking a block or the
+/// mempool costs as much as its transactions, not as the whole state.
+pub struct PendingState<'a> {
+    base: &'a AccountState,
+    balances: HashMap<String, u64>,
+    applied: HashSet<String>,
+}
+
+impl<'a> PendingState<'a> {
+    pub fn new(base: &'a AccountState) -> Self {
+        PendingState {
+            base,
+            balances: HashMap::new(),
+            applied: HashSet::new(),
+        }
+    }
+
+    pub fn balance(&self, address: &str) -> u64 {
+        match self.balances.get(address) {
+            Some(balance) => *balance,
+            None => self.base.balance(address),
+        }
+    }
+
+    fn is_applied(&self, id: &str) -> bool {
+        self.applied.contains(id) || self.base.applied.contains(id)
+    }
+
+    pub fn check_transaction(&self, tx: &Transaction) -> Result<(), StateError> {
+        if tx.amount == 0 {
+            return Err(StateError::ZeroAmount);
+        }
+
+        if tx.from == tx.to {
+            return Err(StateError::SelfTransfer(tx.from.clone()));
+        }
+
+        let id = tx.id();
+        if self.is_applied(&id) {
+            return Err(StateError::Replayed(id));
+        }
+
+        let balance = self.balance(&tx.from);
+        let spent = tx.amount.saturating_add(tx.fee);
+        if balance < spent {
+            return Err(StateError::InsufficientFunds {
+                address: tx.from.clone(),
+                balance,
+                amount: spent,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Like `AccountState::apply_transaction`, leaving the base as it is.
+    pub fn apply_transaction(&mut self, tx: &Transaction) -> Result<(), StateError> {
+        self.check_transaction(tx)?;
+
+        let from = self.balance(&tx.from) - (tx.amount + tx.fee);
+        self.balances.insert(tx.from.clone(), from);
+        let to = self.balance(&tx.to) + tx.amount;
+        self.balances.insert(tx.to.clone(), to);
+        self.applied.insert(tx.id());
+        Ok(())
+    }
+
+    fn into_changes(self) -> Changes {
+        (self.balances, self.applied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::tests::mine;
+    use crate::block::{Block, BlockError};
+    use crate::blockchain::tests::funded_spec;
+    use crate::state::{AccountState, PendingState, StateError};
+    use crate::transaction::Transaction;
+    use libp2p::{identity::Keypair, PeerId};
+
+    #[test]
+    fn test_balances() {
+        let alice = Keypair::generate_ed25519();
+        let alice_addr = PeerId::from(alice.public()).to_string();
+        let genesis = funded_spec(&alice, 100).genesis_block();
+        let mut state = AccountState::genesis(&genesis);
+        assert_eq!(state.balance(&alice_addr), 100);
+        assert_eq!(state.balance("Bob"), 0);
+
+        let pay_bob = Transaction::new_signed(&alice, String::from("Bob"), 60, 1).unwrap();
+        state.apply_transaction(&pay_bob).unwrap();
+        assert_eq!(state.balance(&alice_addr), 39);
+        assert_eq!(state.balance("Bob"), 60);
+
+        assert_eq!(
+            state.apply_transaction(&pay_bob),
+            Err(StateError::Replayed(pay_bob.id()))
+        );
+
+        // The fee counts towards what the sender spends
+        let overspend = Transaction::new_signed(&alice, String::from("Bob"), 39, 1).unwrap();
+        assert!(matches!(
+            state.apply_transaction(&overspend),
+            Err(StateError::InsufficientFunds { balance: 39, amount: 40, .. })
+        ));
+
+        let to_self = Transaction::new_signed(&alice, alice_addr.clone(), 1, 1).unwrap();
+        assert_eq!(
+            state.apply_transaction(&to_self),
+            Err(StateError::SelfTransfer(alice_addr.clone()))
+        );
+
+        let nothing = Transaction::new_signed(&alice, String::from("Bob"), 0, 1).unwrap();
+        assert_eq!(state.apply_transaction(&nothing), Err(StateError::ZeroAmount));
+    }
+
+    #[test]
+    fn test_pending_state() {
+        let alice = Keypair::generate_ed25519();
+        let alice_addr = PeerId::from(alice.public()).to_string();
+        let genesis = funded_spec(&alice, 100).genesis_block();
+        let state = AccountState::genesis(&genesis);
+
+        let mut pending = PendingState::new(&state);
+        let pay_bob = Transaction::new_signed(&alice, String::from("Bob"), 60, 1).unwrap();
+        pending.apply_transaction(&pay_bob).unwrap();
+        assert_eq!(pending.balance(&alice_addr), 39);
+        assert_eq!(pending.balance("Bob"), 60);
+        assert_eq!(
+            pending.check_transaction(&pay_bob),
+            Err(StateError::Replayed(pay_bob.id()))
+        );
+
+        // The state underneath is left as it was
+        assert_eq!(state, AccountState::genesis(&genesis));
+        assert_eq!(state.check_transaction(&pay_bob), Ok(()));
+    }
+
+    #[test]
+    fn test_block_applies_atomically() {
+        let alice = Keypair::generate_ed25519();
+        let alice_addr = PeerId::from(alice.public()).to_string();
+        let genesis = funded_spec(&alice, 100).genesis_block();
+
+        // Each transaction is affordable alone, but not both together
+        let first = Transaction::new_signed(&alice, String::from("Bob"), 70, 0).unwrap();
+        let second = Transaction::new_signed(&alice, String::from("Carol"), 70, 0).unwrap();
+        let block = mine(&genesis.hash, vec![first.clone(), second], 4);
+
+        let mut state = AccountState::genesis(&genesis);
+        assert!(matches!(
+            state.apply_block(&block, 50),
+            Err(BlockError::StateViolation { index: 2, .. })
+        ));
+        assert_eq!(state.balance(&alice_addr), 100);
+        assert_eq!(state.balance("Bob"), 0);
+
+        let block = mine(&genesis.hash, vec![first], 4);
+        state.apply_block(&block, 50).unwrap();
+        assert_eq!(state.balance(&alice_addr), 30);
+        assert_eq!(state.balance("Bob"), 70);
+    }
+
+    #[test]
+    fn test_coinbase() {
+        let alice = Keypair::generate_ed25519();
+        let alice_addr = PeerId::from(alice.public()).to_string();
+        let genesis = funded_spec(&alice, 100).genesis_block();
+        let state = AccountState::genesis(&genesis);
+
+        let tx = Transaction::new_signed(&alice, String::from("Bob"), 10, 5).unwrap();
+        let with_coinbase = |amount| {
+            let coinbase = Transaction::coinbase(String::from("Miner"), amount);
+            Block::new(
+                genesis.hash.clone(),
+                vec![coinbase, tx.clone()],
+                4,
+                0,
+                genesis.header.time(),
+            )
+        };
+
+        let mut paid = state.clone();
+        paid.apply_block(&with_coinbase(55), 50).unwrap();
+        assert_eq!(paid.balance("Miner"), 55);
+        assert_eq!(paid.balance(&alice_addr), 85);
+        assert_eq!(paid.balance("Bob"), 10);
+
+        let mut greedy = state.clone();
+        assert_eq!(
+            greedy.apply_block(&with_coinbase(56), 50),
+            Err(BlockError::StateViolation {
+                index: 0,
+                reason: StateError::ExcessiveCoinbase {
+                    allowed: 55,
+                    claimed: 56
+                }
+            })
+        );
+        assert_eq!(greedy, state);
+
+        let no_coinbase = Block::new(genesis.hash.clone(), vec![tx.clone()], 4, 0, genesis.header.time());
+        assert_eq!(
+            state.clone().apply_block(&no_coinbase, 50),
+            Err(BlockError::MissingCoinbase)
+        );
+    }
+}
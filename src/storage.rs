@@ -0,0 +1,210 @@
+use derive_more::{Display, From};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::block::{Block, BlockError};
+
+const BLOCKS_FILE: &str = "blocks.dat";
+// Length and checksum in front of every record
+const RECORD_HEADER_LEN: usize = 8;
+
+#[derive(Debug, Display, From)]
+pub enum StorageError {
+    #[display(fmt = "can not access block store: {}", _0)]
+    Io(io::Error),
+    #[display(fmt = "can not encode block: {}", _0)]
+    Encode(serde_json::Error),
+    #[display(fmt = "stored chain starts at {} instead of genesis {}", found, expected)]
+    #[from(ignore)]
+    GenesisMismatch { expected: String, found: String },
+    #[display(fmt = "dropped {} bytes of unreadable block data after block {}", bytes, height)]
+    #[from(ignore)]
+    Unreadable { height: usize, bytes: u64 },
+    #[display(fmt = "dropped stored blocks from height {}: {}", height, reason)]
+    #[from(ignore)]
+    InvalidBlock { height: usize, reason: BlockError },
+}
+
+impl std::error::Error for StorageError {}
+
+/// Append-only file of the blocks of the local chain, genesis first. Each
+/// record is the little-endian `u32` length of the JSON encoded block, the
+/// first four bytes of its sha256, then the block itself.
+pub struct BlockStore {
+    file: File,
+    // Start of every record, so the chain can be cut back after a reorg
+    offsets: Vec<u64>,
+    end: u64,
+    // Unreadable bytes cut off the end of the file when it was opened
+    dropped: u64,
+}
+
+impl BlockStore {
+    /// Opens the store in `dir`, creating both if needed, and reads back the
+    /// stored blocks. A torn or corrupt record, as left by a crash during a
+    /// write, is cut off together with everything after it, see
+    /// `dropped_bytes`.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<(Self, Vec<Block>), StorageError> {
+        fs::create_dir_all(&dir)?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.as_ref().join(BLOCKS_FILE))?;
+
+        let mut bytes = vec![];
+        file.read_to_end(&mut bytes)?;
+
+        let mut blocks = vec![];
+        let mut offsets = vec![];
+        let mut end = 0;
+        while let Some((block, len)) = decode_record(&bytes[end..]) {
+            offsets.push(end as u64);
+            blocks.push(block);
+            end += len;
+        }
+
+        let dropped = (bytes.len() - end) as u64;
+        if dropped > 0 {
+            file.set_len(end as u64)?;
+            file.sync_data()?;
+        }
+
+        let store = BlockStore {
+            file,
+            offsets,
+            end: end as u64,
+            dropped,
+        };
+        Ok((store, blocks))
+    }
+
+    /// Number of stored blocks.
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    /// Bytes of unreadable block data `open` cut off after the stored blocks.
+    pub fn dropped_bytes(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Writes `block` as the next height and waits until it is on disk.
+    pub fn append(&mut self, block: &Block) -> Result<(), StorageError> {
+        let payload = serde_json::to_vec(block)?;
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&checksum(&payload));
+        record.extend_from_slice(&payload);
+
+        self.file.seek(SeekFrom::Start(self.end))?;
+        self.file.write_all(&record)?;
+        self.file.sync_data()?;
+
+        self.offsets.push(self.end);
+        self.end += record.len() as u64;
+        Ok(())
+    }
+
+    /// Drops every block from `height` on.
+    pub fn truncate(&mut self, height: usize) -> Result<(), StorageError> {
+        if height >= self.offsets.len() {
+            return Ok(());
+        }
+
+        self.end = self.offsets[height];
+        self.file.set_len(self.end)?;
+        self.file.sync_data()?;
+        self.offsets.truncate(height);
+        Ok(())
+    }
+}
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let digest = Sha256::digest(payload);
+    [digest[0], digest[1], digest[2], digest[3]]
+}
+
+/// The block at the start of `bytes` and the length of its record, if the
+/// record is complete and intact.
+fn decode_record(bytes: &[u8]) -> Option<(Block, usize)> {
+    if bytes.len() < RECORD_HEADER_LEN {
+        return None;
+    }
+
+    let mut len = [0; 4];
+    len.copy_from_slice(&bytes[..4]);
+    let len = u32::from_le_bytes(len) as usize;
+    let payload = bytes.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + len)?;
+    if checksum(payload) != bytes[4..RECORD_HEADER_LEN] {
+        return None;
+    }
+
+    let block = serde_json::from_slice(payload).ok()?;
+    Some((block, RECORD_HEADER_LEN + len))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::block::tests::generate_blocks;
+    use crate::storage::{BlockStore, BLOCKS_FILE};
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    /// Empty directory under the system temp dir, unique to `name`.
+    pub fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("echain-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_block_store() {
+        let dir = temp_dir("block-store");
+        let blocks = generate_blocks();
+
+        let (mut store, stored) = BlockStore::open(&dir).unwrap();
+        assert!(stored.is_empty());
+        for block in &blocks {
+            store.append(block).unwrap();
+        }
+        drop(store);
+
+        let (mut store, stored) = BlockStore::open(&dir).unwrap();
+        assert_eq!(stored, blocks);
+        assert_eq!(store.dropped_bytes(), 0);
+
+        store.truncate(1).unwrap();
+        store.append(&blocks[2]).unwrap();
+        drop(store);
+        let (_, stored) = BlockStore::open(&dir).unwrap();
+        assert_eq!(stored, vec![blocks[0].clone(), blocks[2].clone()]);
+
+        // A crash halfway through a write leaves a torn last record
+        let path = dir.join(BLOCKS_FILE);
+        let intact = fs::metadata(&path).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[200, 1, 0, 0, 1, 2, 3, 4, b'{']).unwrap();
+        drop(file);
+
+        let (mut store, stored) = BlockStore::open(&dir).unwrap();
+        assert_eq!(stored.len(), 2);
+        assert_eq!(store.dropped_bytes(), 9);
+        assert_eq!(fs::metadata(&path).unwrap().len(), intact);
+        store.append(&blocks[1]).unwrap();
+        drop(store);
+        let (store, stored) = BlockStore::open(&dir).unwrap();
+        assert_eq!(store.len(), 3);
+        assert_eq!(stored[2], blocks[1]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,709 @@
+use async_trait::async_trait;
+use libp2p::{
+    core::upgrade::{read_length_prefixed, write_length_prefixed, ProtocolName},
+    futures::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    request_response::RequestResponseCodec,
+    PeerId,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+
+use crate::{
+    block::{Block, BlockError, BlockHeader},
+    blockchain::Blockchain,
+    error::Error,
+    node::{Node, ReorgReport},
+    pow::work,
+    protocol::{ChainStatus, GetHeaders, Message},
+};
+
+/// Most headers sent in one `Headers` response.
+pub const MAX_HEADERS: usize = 512;
+/// Most blocks sent in one `Blocks` response, and asked of one peer at once.
+pub const MAX_BLOCKS_PER_REQUEST: usize = 16;
+/// Headers held before their bodies are fetched, unless more are needed to
+/// outweigh the local chain.
+pub const MAX_PENDING_HEADERS: usize = 2048;
+/// Blocks downloaded ahead of the last one applied, at most.
+pub const DOWNLOAD_WINDOW: usize = 128;
+/// Headers fetched in one round, at most. A peer whose headers do not
+/// outweigh the local chain by then is given up on.
+pub const MAX_ROUND_HEADERS: usize = 16 * MAX_PENDING_HEADERS;
+
+/// Requests to send, each to its peer.
+pub type Requests = Vec<(PeerId, Message)>;
+
+/// Answers a sync request from the local chain. Fails if the message is not
+/// a request.
+pub fn respond(node: &Node, request: &Message) -> Result<Message, Error> {
+    match request {
+        Message::GetStatus(_) => Ok(Message::Status(node.status())),
+        Message::GetHeaders(req) => Ok(Message::Headers(
+            node.blockchain
+                .headers_after(&req.locator, req.max.min(MAX_HEADERS)),
+        )),
+        Message::GetBlocks(hashes) => {
+            let hashes = &hashes[..hashes.len().min(MAX_BLOCKS_PER_REQUEST)];
+            Ok(Message::Blocks(node.blockchain.blocks_by_hash(hashes)))
+        }
+        other => Err(Error::UnexpectedMessage(other.kind())),
+    }
+}
+
+/// Fetching part of one peer's chain: headers first, then the bodies.
+struct Round {
+    /// Peer the headers come from
+    peer: PeerId,
+    /// Height of the last local block the headers build on
+    fork_point: usize,
+    headers: Vec<BlockHeader>,
+    /// The last batch of headers was full, so the peer has more
+    more: bool,
+    fetching_bodies: bool,
+    /// Headers whose bodies were asked for, counted from the first
+    requested: usize,
+    /// Headers whose blocks joined the chain, counted from the first
+    applied: usize,
+    /// Header indices each peer was asked for
+    in_flight: HashMap<PeerId, Vec<usize>>,
+    /// Header indices to ask for again, after a failed or short answer
+    retry: VecDeque<Vec<usize>>,
+    downloaded: HashMap<usize, Block>,
+    /// Peers that failed to send blocks they were asked for
+    excluded: HashSet<PeerId>,
+}
+
+impl Round {
+    fn new(peer: PeerId) -> Self {
+        Round {
+            peer,
+            fork_point: 0,
+            headers: vec![],
+            more: false,
+            fetching_bodies: false,
+            requested: 0,
+            applied: 0,
+            in_flight: HashMap::new(),
+            retry: VecDeque::new(),
+            downloaded: HashMap::new(),
+            excluded: HashSet::new(),
+        }
+    }
+}
+
+/// Headers-first sync. Peers swap `ChainStatus`; from a peer with more work
+/// we fetch and check headers in batches, then download the bodies in chunks
+/// from every peer that has them, keeping at most a window of blocks ahead of
+/// the chain in memory.
+pub struct ChainSync {
+    peers: HashMap<PeerId, ChainStatus>,
+    round: Option<Round>,
+    /// Chain the bodies go to while the headers fork below the local tip. It
+    /// only replaces the local chain once it carries more work.
+    candidate: Option<Blockchain>,
+    header_batch: usize,
+    blocks_per_request: usize,
+    window: usize,
+    max_pending_headers: usize,
+    max_round_headers: usize,
+}
+
+impl ChainSync {
+    pub fn new() -> Self {
+        ChainSync {
+            peers: HashMap::new(),
+            round: None,
+            candidate: None,
+            header_batch: MAX_HEADERS,
+            blocks_per_request: MAX_BLOCKS_PER_REQUEST,
+            window: DOWNLOAD_WINDOW,
+            max_pending_headers: MAX_PENDING_HEADERS,
+            max_round_headers: MAX_ROUND_HEADERS,
+        }
+    }
+
+    /// Records the status `peer` advertised and starts fetching its headers
+    /// if its chain carries more work than ours.
+    pub fn on_status(&mut self, node: &Node, peer: PeerId, status: ChainStatus) -> Requests {
+        if status.genesis_hash != node.blockchain.genesis_hash() {
+            self.peers.remove(&peer);
+            return vec![];
+        }
+
+        let ahead = status.total_work > node.blockchain.total_work();
+        self.peers.insert(peer, status);
+        if self.round.is_none() && ahead {
+            return self.start_round(node, peer);
+        }
+        self.body_requests()
+    }
+
+    fn start_round(&mut self, node: &Node, peer: PeerId) -> Requests {
+        let base = self.candidate.as_ref().unwrap_or(&node.blockchain);
+        let req = GetHeaders {
+            locator: base.locator(),
+            max: self.header_batch,
+        };
+        self.round = Some(Round::new(peer));
+        vec![(peer, Message::GetHeaders(req))]
+    }
+
+    /// Checks a batch of headers and asks for the next one, or for the bodies
+    /// once the headers outweigh the local chain.
+    pub fn on_headers(
+        &mut self,
+        node: &Node,
+        peer: PeerId,
+        headers: Vec<BlockHeader>,
+    ) -> Result<Requests, Error> {
+        let round = match self.round.as_mut() {
+            Some(round) if round.peer == peer && !round.fetching_bodies => round,
+            _ => return Ok(vec![]),
+        };
+        let base = self.candidate.as_ref().unwrap_or(&node.blockchain);
+        let full = headers.len() >= self.header_batch;
+
+        let checked = match headers.first() {
+            None if round.headers.is_empty() => Ok(()),
+            Some(first) if round.headers.is_empty() => match base.height_of(&first.prev_hash) {
+                Some(height) => {
+                    round.fork_point = height;
+                    round.headers = headers;
+                    base.check_headers(height, &round.headers, 0)
+                }
+                None => Err(BlockError::PrevHashMismatch {
+                    expected: base.tip_hash().to_string(),
+                    found: first.prev_hash.clone(),
+                }),
+            },
+            _ => {
+                let from = round.headers.len();
+                round.headers.extend(headers);
+                base.check_headers(round.fork_point, &round.headers, from)
+            }
+        };
+        if let Err(e) = checked {
+            self.round = None;
+            return Err(e.into());
+        }
+
+        round.more = full;
+        let shared: u128 = base.chain[..=round.fork_point]
+            .iter()
+            .map(|block| work(block.header.difficulty))
+            .sum();
+        let offered: u128 = round.headers.iter().map(|header| work(header.difficulty)).sum();
+        let outweighs = shared + offered > node.blockchain.total_work();
+
+        let capped = round.headers.len() >= self.max_round_headers;
+        if full && !capped && (round.headers.len() < self.max_pending_headers || !outweighs) {
+            let last = round.headers.last().expect("batch was full").hash();
+            let req = GetHeaders {
+                locator: vec![last],
+                max: self.header_batch,
+            };
+            return Ok(vec![(peer, Message::GetHeaders(req))]);
+        }
+
+        if !outweighs {
+            self.round = None;
+            self.candidate = None;
+            return Ok(vec![]);
+        }
+
+        if round.fork_point + 1 < base.len() {
+            self.candidate = Some(base.with_blocks(base.chain[..=round.fork_point].to_vec()));
+        }
+        round.fetching_bodies = true;
+        Ok(self.body_requests())
+    }
+
+    /// Hands out the next chunks of bodies to every idle peer that has them.
+    /// Drops the round if none can take what is still missing, so the next
+    /// status may start over.
+    fn body_requests(&mut self) -> Requests {
+        let round = match self.round.as_mut() {
+            Some(round) if round.fetching_bodies => round,
+            _ => return vec![],
+        };
+
+        let mut requests = vec![];
+        for (peer, status) in &self.peers {
+            if round.in_flight.contains_key(peer) || round.excluded.contains(peer) {
+                continue;
+            }
+            // Heights this peer has, as indices into the headers
+            let known = status.height.saturating_sub(round.fork_point);
+
+            let chunk = match round.retry.iter().position(|chunk| chunk.iter().all(|&i| i < known)) {
+                Some(position) => round.retry.remove(position).expect("position is in range"),
+                None => {
+                    let end = (round.requested + self.blocks_per_request)
+                        .min(round.headers.len())
+                        .min(round.applied + self.window)
+                        .min(known);
+                    if end <= round.requested {
+                        continue;
+                    }
+                    let chunk = (round.requested..end).collect();
+                    round.requested = end;
+                    chunk
+                }
+            };
+
+            let hashes = chunk.iter().map(|&i| round.headers[i].hash()).collect();
+            round.in_flight.insert(*peer, chunk);
+            requests.push((*peer, Message::GetBlocks(hashes)));
+        }
+
+        // Nothing is on its way and no peer left can send what is missing
+        let stalled = requests.is_empty()
+            && round.in_flight.is_empty()
+            && round.applied < round.headers.len();
+        if stalled {
+            self.round = None;
+            self.candidate = None;
+        }
+        requests
+    }
+
+    /// Takes the blocks `peer` sent and applies every one that is next in
+    /// line, then asks for more. Also returns the reorganisation, if the
+    /// blocks finished a heavier branch the node switched to.
+    pub fn on_blocks(
+        &mut self,
+        node: &mut Node,
+        peer: PeerId,
+        blocks: Vec<Block>,
+    ) -> Result<(Requests, Option<ReorgReport>), Error> {
+        let round = match self.round.as_mut() {
+            Some(round) => round,
+            None => return Ok((vec![], None)),
+        };
+        let chunk = match round.in_flight.remove(&peer) {
+            Some(chunk) => chunk,
+            None => return Ok((vec![], None)),
+        };
+
+        let mut by_hash: HashMap<String, Block> = blocks
+            .into_iter()
+            .map(|block| (block.hash.clone(), block))
+            .collect();
+        let mut missing = vec![];
+        for i in chunk {
+            match by_hash.remove(&round.headers[i].hash()) {
+                Some(block) if block.header == round.headers[i] => {
+                    round.downloaded.insert(i, block);
+                }
+                _ => missing.push(i),
+            }
+        }
+        if !missing.is_empty() {
+            round.excluded.insert(peer);
+            round.retry.push_back(missing);
+        }
+
+        while let Some(block) = round.downloaded.remove(&round.applied) {
+            let applied = match self.candidate.as_mut() {
+                Some(candidate) => candidate.add_block(block),
+                // Gossip got it here first
+                None if node.blockchain.contains(&block.hash) => Ok(()),
+                None if block.header.prev_hash == node.blockchain.tip_hash() => {
+                    node.receive_block(block).map(|_| node.mark_synced())
+                }
+                // Gossip moved the tip off the parent, so the rest goes to
+                // a candidate that has to outweigh the new tip
+                None => match node.blockchain.height_of(&block.header.prev_hash) {
+                    Some(parent) => {
+                        let mut candidate = node
+                            .blockchain
+                            .with_blocks(node.blockchain.chain[..=parent].to_vec());
+                        let applied = candidate.add_block(block);
+                        self.candidate = Some(candidate);
+                        applied
+                    }
+                    None => Err(BlockError::PrevHashMismatch {
+                        expected: node.blockchain.tip_hash().to_string(),
+                        found: block.header.prev_hash.clone(),
+                    }),
+                },
+            };
+            if let Err(e) = applied {
+                self.round = None;
+                self.candidate = None;
+                return Err(e.into());
+            }
+            round.applied += 1;
+        }
+
+        if round.applied < round.headers.len() {
+            return Ok((self.body_requests(), None));
+        }
+
+        let (more, peer) = (round.more, round.peer);
+        self.round = None;
+        let mut report = None;
+        if let Some(candidate) = self.candidate.take() {
+            if candidate.total_work() > node.blockchain.total_work() {
+                report = Some(node.resolve_chain_conflict(&candidate)?);
+            } else {
+                self.candidate = Some(candidate);
+            }
+        }
+
+        if more {
+            return Ok((self.start_round(node, peer), report));
+        }
+        self.candidate = None;
+        Ok((vec![], report))
+    }
+
+    /// Forgets `peer` after a request to it failed, handing whatever it was
+    /// asked for to the other peers.
+    pub fn on_failure(&mut self, peer: &PeerId) -> Requests {
+        self.peers.remove(peer);
+        if let Some(round) = self.round.as_mut() {
+            if let Some(chunk) = round.in_flight.remove(peer) {
+                round.retry.push_back(chunk);
+            }
+            let stalled = if round.fetching_bodies {
+                self.peers.is_empty()
+            } else {
+                round.peer == *peer
+            };
+            if stalled {
+                self.round = None;
+                self.candidate = None;
+                return vec![];
+            }
+        }
+        self.body_requests()
+    }
+}
+
+impl Default for ChainSync {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Largest request or response read from a peer.
+pub const MAX_SYNC_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Point-to-point protocol peers fetch chain data from each other with.
+#[derive(Debug, Clone)]
+pub struct SyncProtocol;
+
+impl ProtocolName for SyncProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/echain/sync/1"
+    }
+}
+
+/// Carries one length-prefixed `Message` envelope each way.
+#[derive(Clone, Default)]
+pub struct SyncCodec;
+
+async fn read_message<T>(io: &mut T) -> io::Result<Message>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    let bytes = read_length_prefixed(io, MAX_SYNC_MESSAGE_SIZE).await?;
+    Message::decode(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+async fn write_message<T>(io: &mut T, message: Message) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+{
+    write_length_prefixed(io, message.encode()).await?;
+    io.close().await
+}
+
+#[async_trait]
+impl RequestResponseCodec for SyncCodec {
+    type Protocol = SyncProtocol;
+    type Request = Message;
+    type Response = Message;
+
+    async fn read_request<T>(&mut self, _: &SyncProtocol, io: &mut T) -> io::Result<Message>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_message(io).await
+    }
+
+    async fn read_response<T>(&mut self, _: &SyncProtocol, io: &mut T) -> io::Result<Message>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_message(io).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &SyncProtocol,
+        io: &mut T,
+        req: Message,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_message(io, req).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &SyncProtocol,
+        io: &mut T,
+        res: Message,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_message(io, res).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::tests::mine_at;
+    use crate::blockchain::tests::test_spec;
+    use crate::blockchain::Blockchain;
+    use crate::node::Node;
+    use crate::protocol::Message;
+    use crate::sync::{respond, ChainSync, Requests, SyncCodec, SyncProtocol};
+    use libp2p::futures::{executor::block_on, io::Cursor};
+    use libp2p::request_response::RequestResponseCodec;
+    use libp2p::PeerId;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    fn extend(chain: &Blockchain, blocks: u64, spacing: u64) -> Blockchain {
+        let mut chain = chain.clone();
+        for _ in 0..blocks {
+            let time = chain.chain.last().unwrap().header.time() + Duration::from_secs(spacing);
+            let block = mine_at(chain.tip_hash(), vec![], chain.next_difficulty(), time);
+            chain.add_block(block).unwrap();
+        }
+        chain
+    }
+
+    /// Serves `requests` from `peers` until the sync asks for nothing more.
+    /// Returns how many block requests each peer answered.
+    fn run(
+        sync: &mut ChainSync,
+        node: &mut Node,
+        peers: &HashMap<PeerId, Node>,
+        requests: Requests,
+    ) -> HashMap<PeerId, usize> {
+        let mut served = HashMap::new();
+        let mut queue = requests;
+        while let Some((peer, request)) = queue.pop() {
+            if let Message::GetBlocks(_) = request {
+                *served.entry(peer).or_default() += 1;
+            }
+            let next = match respond(&peers[&peer], &request).unwrap() {
+                Message::Status(status) => sync.on_status(node, peer, status),
+                Message::Headers(headers) => sync.on_headers(node, peer, headers).unwrap(),
+                Message::Blocks(blocks) => sync.on_blocks(node, peer, blocks).unwrap().0,
+                other => panic!("unexpected response {:?}", other),
+            };
+            queue.extend(next);
+        }
+        served
+    }
+
+    fn small_batches() -> ChainSync {
+        ChainSync {
+            header_batch: 8,
+            blocks_per_request: 3,
+            window: 6,
+            max_pending_headers: 16,
+            ..ChainSync::new()
+        }
+    }
+
+    #[test]
+    fn test_catch_up_from_several_peers() {
+        let base = Blockchain::new(&test_spec(), 0, 256);
+        let ahead = extend(&base, 40, 30);
+        let peers: HashMap<PeerId, Node> = (0..2)
+            .map(|_| (PeerId::random(), Node::new(ahead.clone())))
+            .collect();
+
+        let mut node = Node::new(base);
+        let mut sync = small_batches();
+        let requests = peers
+            .iter()
+            .flat_map(|(peer, other)| sync.on_status(&node, *peer, other.status()))
+            .collect();
+        let served = run(&mut sync, &mut node, &peers, requests);
+
+        assert!(node.blockchain == ahead);
+        assert!(node.last_time_synced > 0.0);
+        assert!(sync.round.is_none());
+        // Both peers were asked for bodies
+        assert_eq!(served.len(), 2);
+    }
+
+    #[test]
+    fn test_reorg_onto_heavier_fork() {
+        let base = Blockchain::new(&test_spec(), 0, 256);
+        let local = extend(&base, 3, 30);
+        let heavier = extend(&base, 20, 29);
+        let peer = PeerId::random();
+        let peers: HashMap<PeerId, Node> = vec![(peer, Node::new(heavier.clone()))]
+            .into_iter()
+            .collect();
+
+        let mut node = Node::new(local.clone());
+        let mut sync = small_batches();
+        let requests = sync.on_status(&node, peer, peers[&peer].status());
+        run(&mut sync, &mut node, &peers, requests);
+        assert!(node.blockchain == heavier);
+        assert!(sync.candidate.is_none());
+
+        // A lighter peer is not synced from
+        let node = Node::new(local);
+        let mut sync = small_batches();
+        assert!(sync.on_status(&node, peer, Node::new(base).status()).is_empty());
+
+        // Headers that do not build on anything we have are refused
+        assert_eq!(sync.on_status(&node, peer, peers[&peer].status()).len(), 1);
+        let mut headers: Vec<_> = heavier.chain[5..10]
+            .iter()
+            .map(|block| block.header.clone())
+            .collect();
+        assert!(sync.on_headers(&node, peer, headers.clone()).is_err());
+        assert!(sync.round.is_none());
+
+        assert_eq!(sync.on_status(&node, peer, peers[&peer].status()).len(), 1);
+        headers = heavier.chain[1..10]
+            .iter()
+            .map(|block| block.header.clone())
+            .collect();
+        headers[4].nonce += 1;
+        assert!(sync.on_headers(&node, peer, headers).is_err());
+        assert_eq!(node.blockchain.len(), 4);
+    }
+
+    #[test]
+    fn test_short_answer_from_last_peer() {
+        let base = Blockchain::new(&test_spec(), 0, 256);
+        let ahead = Node::new(extend(&base, 6, 30));
+        let peer = PeerId::random();
+        let node = Node::new(base);
+        let mut sync = small_batches();
+
+        let requests = sync.on_status(&node, peer, ahead.status());
+        let headers = match respond(&ahead, &requests[0].1).unwrap() {
+            Message::Headers(headers) => headers,
+            other => panic!("unexpected response {:?}", other),
+        };
+        let requests = sync.on_headers(&node, peer, headers).unwrap();
+        assert!(matches!(requests[..], [(_, Message::GetBlocks(_))]));
+
+        // Nobody else has the blocks it left out, so the round is over
+        let mut node = node;
+        let (requests, report) = sync.on_blocks(&mut node, peer, vec![]).unwrap();
+        assert!(requests.is_empty() && report.is_none());
+        assert!(sync.round.is_none());
+        assert_eq!(sync.on_status(&node, peer, ahead.status()).len(), 1);
+    }
+
+    #[test]
+    fn test_tip_moves_during_sync() {
+        let base = Blockchain::new(&test_spec(), 0, 256);
+        let ahead = extend(&base, 6, 30);
+        let peer = PeerId::random();
+        let peers: HashMap<PeerId, Node> = vec![(peer, Node::new(ahead.clone()))]
+            .into_iter()
+            .collect();
+        let mut node = Node::new(base.clone());
+        let mut sync = small_batches();
+
+        let mut queue = sync.on_status(&node, peer, peers[&peer].status());
+        let mut gossiped = false;
+        let mut reports = vec![];
+        while let Some((peer, request)) = queue.pop() {
+            let next = match respond(&peers[&peer], &request).unwrap() {
+                Message::Headers(headers) => sync.on_headers(&node, peer, headers).unwrap(),
+                Message::Blocks(blocks) => {
+                    let (next, report) = sync.on_blocks(&mut node, peer, blocks).unwrap();
+                    reports.extend(report);
+                    // Another miner's block lands on the tip while bodies
+                    // are still coming
+                    if !gossiped {
+                        let tip = node.blockchain.chain.last().unwrap();
+                        let time = tip.header.time() + Duration::from_secs(1);
+                        let block = mine_at(tip.hash.as_str(), vec![], 4, time);
+                        node.receive_block(block).unwrap();
+                        gossiped = true;
+                    }
+                    next
+                }
+                other => panic!("unexpected response {:?}", other),
+            };
+            queue.extend(next);
+        }
+
+        assert!(node.blockchain == ahead);
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].removed.len(), 1);
+        assert!(sync.round.is_none());
+        assert!(sync.candidate.is_none());
+    }
+
+    #[test]
+    fn test_header_cap() {
+        let base = Blockchain::new(&test_spec(), 0, 256);
+        let local = extend(&base, 40, 30);
+        let other = Node::new(extend(&base, 40, 31));
+        let peer = PeerId::random();
+        let node = Node::new(local);
+        let mut sync = ChainSync {
+            max_round_headers: 16,
+            ..small_batches()
+        };
+
+        // Claims more work than its headers carry
+        let mut status = other.status();
+        status.total_work *= 2;
+        let mut requests = sync.on_status(&node, peer, status);
+        let mut fetched = 0;
+        while let Some((_, request)) = requests.pop() {
+            let headers = match respond(&other, &request).unwrap() {
+                Message::Headers(headers) => headers,
+                other => panic!("unexpected response {:?}", other),
+            };
+            fetched += headers.len();
+            requests = sync.on_headers(&node, peer, headers).unwrap();
+        }
+        assert_eq!(fetched, 16);
+        assert!(sync.round.is_none());
+    }
+
+    #[test]
+    fn test_codec_round_trip() {
+        block_on(async {
+            let mut codec = SyncCodec;
+            let mut wire = Cursor::new(vec![]);
+            let request = Message::GetBlocks(vec![String::from("hash")]);
+            codec
+                .write_request(&SyncProtocol, &mut wire, request)
+                .await
+                .unwrap();
+
+            wire.set_position(0);
+            match codec.read_request(&SyncProtocol, &mut wire).await.unwrap() {
+                Message::GetBlocks(hashes) => assert_eq!(hashes, vec!["hash"]),
+                other => panic!("decoded as {:?}", other),
+            }
+
+            // A garbled frame is rejected rather than misread
+            let mut garbled = Cursor::new(vec![3, b'{', b'}', b'x']);
+            assert!(codec.read_response(&SyncProtocol, &mut garbled).await.is_err());
+        });
+    }
+}
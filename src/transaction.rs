@@ -1,13 +1,217 @@
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use derive_more::Display;
+use libp2p::{identity, identity::error::SigningError, PeerId};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::cmp::PartialEq;
 
+use crate::block::{encode_bytes, encode_str};
+
+/// Sender of coinbase transactions, which pay the miner of a block.
+pub const COINBASE_ADDRESS: &str = "coinbase";
+
+/// Reason a transaction failed `Transaction::verify`.
+#[derive(Clone, Display, PartialEq, Debug)]
+pub enum TransactionError {
+    #[display(fmt = "public key can not be decoded")]
+    InvalidPublicKey,
+    #[display(fmt = "public key does not belong to sender {}", _0)]
+    SenderMismatch(String),
+    #[display(fmt = "signature does not match")]
+    InvalidSignature,
+}
+
+impl std::error::Error for TransactionError {}
+
 #[derive(Serialize, Deserialize, Clone, Display, PartialEq, Debug)]
-#[display(fmt = "from {} to {} amt {}", from, to, amount)]
+#[display(fmt = "from {} to {} amt {} fee {}", from, to, amount, fee)]
 pub struct Transaction {
+    /// Peer id of the sender, derived from `public_key`
     pub from: String,
     pub to: String,
+    /// Only whole milliseconds count, see `encode`
     pub time: SystemTime,
-    pub amount: i32,
+    pub amount: u64,
+    /// Paid by the sender on top of `amount`, collected by the miner
+    pub fee: u64,
+    /// Protobuf encoded libp2p public key of the sender
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl Transaction {
+    /// Builds a transaction from the owner of `keypair` and signs it.
+    pub fn new_signed(
+        keypair: &identity::Keypair,
+        to: String,
+        amount: u64,
+        fee: u64,
+    ) -> Result<Self, SigningError> {
+        let public_key = keypair.public();
+        let mut tx = Transaction {
+            from: PeerId::from(public_key.clone()).to_string(),
+            to,
+            time: now_millis(),
+            amount,
+            fee,
+            public_key: public_key.into_protobuf_encoding(),
+            signature: vec![],
+        };
+        tx.signature = keypair.sign(&tx.signing_bytes())?;
+        Ok(tx)
+    }
+
+    /// Unsigned transaction crediting the miner of a block with `amount`.
+    pub fn coinbase(to: String, amount: u64) -> Self {
+        Transaction {
+            from: String::from(COINBASE_ADDRESS),
+            to,
+            time: now_millis(),
+            amount,
+            fee: 0,
+            public_key: vec![],
+            signature: vec![],
+        }
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.from == COINBASE_ADDRESS
+    }
+
+    /// Milliseconds since the unix epoch of `time`.
+    pub fn timestamp(&self) -> u64 {
+        self.time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64)
+    }
+
+    /// Canonical bytes of the signed transaction, laid out like
+    /// `BlockHeader::encode`: `signing_bytes`, then the signature with its
+    /// length prefix.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = self.signing_bytes();
+        encode_bytes(&mut bytes, &self.signature);
+        bytes
+    }
+
+    /// Hash of the whole signed transaction.
+    pub fn id(&self) -> String {
+        format!("{:x}", Sha256::digest(self.encode()))
+    }
+
+    /// Bytes the transaction takes up in a block.
+    pub fn size(&self) -> usize {
+        self.encode().len()
+    }
+
+    /// Fee paid per 1000 bytes of `size`, which block assembly ranks by.
+    pub fn fee_rate(&self) -> u64 {
+        (self.fee as u128 * 1000 / self.size().max(1) as u128) as u64
+    }
+
+    /// Everything the signature covers: each field but the signature in
+    /// declaration order, `time` as milliseconds. Integers are little-endian
+    /// and strings and byte strings carry a little-endian `u32` length
+    /// prefix.
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(128 + self.public_key.len());
+        encode_str(&mut bytes, &self.from);
+        encode_str(&mut bytes, &self.to);
+        bytes.extend_from_slice(&self.timestamp().to_le_bytes());
+        bytes.extend_from_slice(&self.amount.to_le_bytes());
+        bytes.extend_from_slice(&self.fee.to_le_bytes());
+        encode_bytes(&mut bytes, &self.public_key);
+        bytes
+    }
+
+    /// Checks that `public_key` belongs to the sender and signed the rest of
+    /// the transaction.
+    pub fn verify(&self) -> Result<(), TransactionError> {
+        let public_key = identity::PublicKey::from_protobuf_encoding(&self.public_key)
+            .map_err(|_| TransactionError::InvalidPublicKey)?;
+
+        if PeerId::from(public_key.clone()).to_string() != self.from {
+            return Err(TransactionError::SenderMismatch(self.from.clone()));
+        }
+
+        if !public_key.verify(&self.signing_bytes(), &self.signature) {
+            return Err(TransactionError::InvalidSignature);
+        }
+
+        Ok(())
+    }
+}
+
+/// The current time, cut to what `encode` keeps of it.
+fn now_millis() -> SystemTime {
+    let since = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    SystemTime::UNIX_EPOCH + Duration::from_millis(since.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transaction::{Transaction, TransactionError};
+    use libp2p::identity::Keypair;
+    use sha2::{Digest, Sha256};
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_signed_transaction() {
+        let alice = Keypair::generate_ed25519();
+        let bob = Keypair::generate_ed25519();
+
+        let tx = Transaction::new_signed(&alice, String::from("Bob"), 32, 1).unwrap();
+        assert!(tx.verify().is_ok());
+
+        let mut tampered = tx.clone();
+        tampered.amount = 1000;
+        assert_eq!(tampered.verify(), Err(TransactionError::InvalidSignature));
+
+        let mut redirected = tx.clone();
+        redirected.to = String::from("Mallory");
+        assert_eq!(redirected.verify(), Err(TransactionError::InvalidSignature));
+
+        let mut impersonated = Transaction::new_signed(&bob, String::from("Bob"), 32, 1).unwrap();
+        impersonated.from = tx.from.clone();
+        assert_eq!(
+            impersonated.verify(),
+            Err(TransactionError::SenderMismatch(tx.from.clone()))
+        );
+
+        let mut unsigned = tx.clone();
+        unsigned.public_key = vec![];
+        assert_eq!(unsigned.verify(), Err(TransactionError::InvalidPublicKey));
+    }
+
+    #[test]
+    fn test_transaction_encoding() {
+        let tx = Transaction {
+            from: String::from("a"),
+            to: String::from("bc"),
+            time: SystemTime::UNIX_EPOCH + Duration::from_millis(0x0102),
+            amount: 3,
+            fee: 4,
+            public_key: vec![5],
+            signature: vec![6, 7],
+        };
+        assert_eq!(
+            tx.encode(),
+            vec![
+                1, 0, 0, 0, b'a', 2, 0, 0, 0, b'b', b'c', 2, 1, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0,
+                0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 5, 2, 0, 0, 0, 6, 7,
+            ]
+        );
+        assert_eq!(tx.signing_bytes(), tx.encode()[..tx.encode().len() - 6]);
+        assert_eq!(tx.size(), 46);
+        assert_eq!(tx.id(), format!("{:x}", Sha256::digest(tx.encode())));
+
+        // Below a millisecond the time is not part of the transaction
+        let later = Transaction {
+            time: tx.time + Duration::from_micros(500),
+            ..tx.clone()
+        };
+        assert_eq!(later.id(), tx.id());
+    }
 }